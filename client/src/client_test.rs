@@ -54,7 +54,7 @@ impl Autocomplete for SpeciesSuggesterRemote {
                 let jsonres = response.json::<FuzzyAutocompleteResponse>();
                 match jsonres {
                     Ok(inner) => {
-                        return Ok(inner.matches.into_iter().map(|data| format!("{}    ::::    {}", data.string, data.data.to_string())).collect())
+                        return Ok(inner.matches.into_iter().map(|data| format!("{}    ::::    {}", data.string, data.data)).collect())
                     },
                     Err(e) => return Err(e.into()),
                 }
@@ -74,10 +74,7 @@ impl Autocomplete for SpeciesSuggesterRemote {
         highlighted_suggestion: Option<String>,
     ) -> Result<inquire::autocompletion::Replacement, inquire::CustomUserError> {
 
-        Ok(match highlighted_suggestion {
-            Some(suggestion) => inquire::autocompletion::Replacement::Some(suggestion),
-            None => None,
-        })
+        Ok(highlighted_suggestion)
     }
 }
 
@@ -165,7 +162,7 @@ pub fn client_tui() {
             .with_help_message("A positive number")
             .with_default("1")
             .with_validator(|input: &str| {
-                match u32::from_str_radix(input, 10) {
+                match input.parse::<u32>() {
                     Ok(n) => if n > 0 {
                         Ok(Validation::Valid)
                     } else {
@@ -182,7 +179,7 @@ pub fn client_tui() {
         println!("\tPOST to addr {}", fuzzy_match_route);
         let req_payload = FuzzyMatchRequest{ 
             strings: query_strings, 
-            n_first_results: u32::from_str_radix(limit.as_str(), 10).unwrap() 
+            n_first_results: limit.parse::<u32>().unwrap() 
         };
         println!("\tpayload: {}", serde_json::to_string(&req_payload).unwrap());
         
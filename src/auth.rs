@@ -0,0 +1,151 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{extract::{MatchedPath, Request, State}, middleware::Next, response::Response};
+use parking_lot::Mutex;
+use serde::Deserialize;
+
+use crate::{rate_limit::TokenBucket, routes::error::ApiError, AppState};
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+// answered without a key nor rate limiting, for load balancers and orchestrators probes
+pub const PUBLIC_ROUTES: [&str; 2] = ["/healthz", "/readyz"];
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ApiKeysFile {
+    keys: Vec<ApiKeyEntry>,
+}
+
+/*
+    One entry of the keys file, see api_keys.example.toml.
+    routes are the paths the key may call, "*" allowing all of them.
+ */
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ApiKeyEntry {
+    name: String,
+    key: String,
+    routes: Vec<String>,
+    max_batch_size: Option<usize>,
+    requests_per_minute: Option<u32>,
+}
+
+/**
+ * A key that passed authentication, added to the request extensions for the handlers.
+ */
+#[derive(Debug)]
+pub struct ApiKey {
+    pub name: String,
+    routes: Vec<String>,
+    // number of strings accepted by /fuzzy_match and /exact_match, max_batch_size from the config when None
+    pub max_batch_size: Option<usize>,
+    quota: Option<Mutex<TokenBucket>>,
+}
+
+impl ApiKey {
+    fn allows(&self, route: &str) -> bool {
+        return self.routes.iter().any(|allowed| allowed == "*" || allowed == route);
+    }
+
+    pub fn has_quota(&self) -> bool {
+        return self.quota.is_some();
+    }
+}
+
+/**
+ * Every key of the keys file, indexed by the key itself.
+ */
+pub struct ApiKeys {
+    by_key: HashMap<String, Arc<ApiKey>>,
+}
+
+impl ApiKeys {
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path).map_err(|e| format!("Cannot read {}: {}", path, e))?;
+        let file = toml::from_str::<ApiKeysFile>(&content).map_err(|e| format!("Invalid keys file {}: {}", path, e))?;
+
+        let mut by_key = HashMap::new();
+        for entry in file.keys {
+            if entry.key.is_empty() {
+                return Err(format!("Key {:?} is empty", entry.name));
+            }
+            if entry.requests_per_minute == Some(0) || entry.max_batch_size == Some(0) {
+                return Err(format!("Key {:?}: requests_per_minute and max_batch_size must be > 0", entry.name));
+            }
+            if let Some(route) = entry.routes.iter().find(|route| *route != "*" && !route.starts_with('/')) {
+                return Err(format!("Key {:?}: invalid route {:?}", entry.name, route));
+            }
+
+            let api_key = ApiKey {
+                name: entry.name,
+                routes: entry.routes,
+                max_batch_size: entry.max_batch_size,
+                quota: entry.requests_per_minute.map(|rpm| Mutex::new(TokenBucket::per_minute(rpm))),
+            };
+            if by_key.insert(entry.key, Arc::new(api_key)).is_some() {
+                return Err("The same key is given twice".to_owned());
+            }
+        }
+
+        return Ok(ApiKeys { by_key });
+    }
+
+    pub fn len(&self) -> usize {
+        return self.by_key.len();
+    }
+}
+
+/**
+ * Middleware checking the API key of each request, when a keys file is configured.
+ * The key must allow the route, and have some quota left. It is then available to handlers as Extension<Arc<ApiKey>>.
+ */
+pub async fn authenticate(
+    State(appstate): State<AppState>,
+    matched_path: Option<MatchedPath>,
+    mut request: Request,
+    next: Next,
+    )
+-> Result<Response, ApiError> {
+    let Some(api_keys) = &appstate.api_keys else {
+        return Ok(next.run(request).await);
+    };
+    let route = match &matched_path {
+        Some(matched_path) => matched_path.as_str(),
+        None => "unmatched",
+    };
+    if PUBLIC_ROUTES.contains(&route) {
+        return Ok(next.run(request).await);
+    }
+
+    let rejections = &appstate.metrics.api_key_rejections;
+    let api_key = match request.headers().get(API_KEY_HEADER).map(|value| value.to_str()) {
+        None => {
+            rejections.with_label_values(&["", "missing_api_key"]).inc();
+            return Err(ApiError::missing_api_key());
+        },
+        Some(key) => match key.ok().and_then(|key| api_keys.by_key.get(key)) {
+            Some(api_key) => api_key.clone(),
+            None => {
+                rejections.with_label_values(&["", "invalid_api_key"]).inc();
+                return Err(ApiError::invalid_api_key());
+            },
+        },
+    };
+
+    if !api_key.allows(route) {
+        rejections.with_label_values(&[&api_key.name, "route_not_allowed"]).inc();
+        return Err(ApiError::route_not_allowed(&api_key.name, route));
+    }
+    if let Some(quota) = &api_key.quota {
+        let taken = quota.lock().try_take();
+        if let Err(retry_after) = taken {
+            rejections.with_label_values(&[&api_key.name, "rate_limited"]).inc();
+            return Err(ApiError::rate_limited(retry_after));
+        }
+    }
+
+    tracing::Span::current().record("api_key", api_key.name.as_str());
+    appstate.metrics.api_key_requests.with_label_values(&[&api_key.name, route]).inc();
+    request.extensions_mut().insert(api_key);
+    return Ok(next.run(request).await);
+}
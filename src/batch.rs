@@ -0,0 +1,275 @@
+use std::{cmp::Reverse, collections::{BinaryHeap, HashMap}};
+
+use nucleo_matcher::{pattern::{CaseMatching, Normalization, Pattern}, Matcher, Utf32String};
+
+use crate::{engine::{to_haystack, to_query, to_query_authorship}, io::EngineInputData, names::{self, Authorship, ParsedName}};
+
+// score, haystack length, homonym group, authorship distance, index: lower ranks first
+type Rank = (Reverse<u32>, usize, usize, (usize, u16), usize);
+
+/**
+ * One-shot matcher for batches: every query is scored against the whole dataset with nucleo_matcher directly,
+ * without Nucleo's worker, ticks and snapshots. Immutable once built, so a single one is shared by all requests,
+ * each thread bringing its own Matcher (see new_matcher).
+ * Results are ranked like Nucleo does: best score first, then shortest string, then dataset order.
+ * When matching canonical names and the query has an authorship, homonyms are ranked like names::tie_break does:
+ * grouped at the place of the first one in the dataset, closest authorship first.
+ * Abbreviated genera ("E. coli") can be expanded to the genera of the dataset, see fuzzy_match_expanded.
+ */
+pub struct BatchMatcher {
+    haystacks: Vec<Utf32String>,
+    items: Vec<EngineInputData>,
+    // see EngineWrapper
+    canonical: bool,
+    // for each item, the index of the first one with the same haystack
+    homonym_groups: Vec<usize>,
+    // for each item, empty unless canonical
+    authorships: Vec<Authorship>,
+    // lowercased specific epithet, to the genera having a species with it, in dataset order
+    genera_by_epithet: HashMap<String, Vec<String>>,
+    // an abbreviation is expanded to at most this number of genera
+    max_expansions: usize,
+}
+
+impl BatchMatcher {
+    pub fn new(input_data: &[EngineInputData], canonical: bool, max_expansions: usize) -> Self {
+        let haystacks = input_data.iter()
+            .map(|input_data| to_haystack(input_data, canonical))
+            .collect::<Vec<Utf32String>>();
+
+        let mut first_homonyms: HashMap<&Utf32String, usize> = HashMap::new();
+        let homonym_groups = haystacks.iter().enumerate()
+            .map(|(index, haystack)| *first_homonyms.entry(haystack).or_insert(index))
+            .collect::<Vec<usize>>();
+
+        let parsed_names = input_data.iter()
+            .map(|input_data| names::parse(&input_data.string))
+            .collect::<Vec<ParsedName>>();
+        let authorships = match canonical {
+            true => parsed_names.iter().map(Authorship::of).collect(),
+            false => vec![],
+        };
+
+        let mut genera_by_epithet: HashMap<String, Vec<String>> = HashMap::new();
+        for parsed_name in &parsed_names {
+            let (Some(genus), Some(epithet)) = (&parsed_name.uninomial, &parsed_name.specific_epithet) else {
+                continue;
+            };
+            let genera = genera_by_epithet.entry(epithet.to_lowercase()).or_default();
+            if !genera.contains(genus) {
+                genera.push(genus.clone());
+            }
+        }
+
+        return BatchMatcher {
+            items: input_data.to_vec(),
+            canonical,
+            homonym_groups,
+            authorships,
+            genera_by_epithet,
+            max_expansions,
+            haystacks,
+        };
+    }
+
+    // scratch space for matching, one per thread
+    pub fn new_matcher() -> Matcher {
+        return Matcher::new(nucleo_matcher::Config::DEFAULT);
+    }
+
+    /**
+     * The limit best matches of input, same as EngineWrapper::fuzzy_match.
+     */
+    pub fn fuzzy_match(&self, matcher: &mut Matcher, input: String, limit: usize) -> Vec<EngineInputData> {
+        return self.fuzzy_match_scored(matcher, input, limit).into_iter()
+            .map(|(_, item)| item)
+            .collect();
+    }
+
+    // same as fuzzy_match, along with the score of each match
+    pub fn fuzzy_match_scored(&self, matcher: &mut Matcher, input: String, limit: usize) -> Vec<(u32, EngineInputData)> {
+        return self.top_k(matcher, input, limit).into_iter()
+            .map(|(Reverse(score), _, _, _, index)| (score, self.items[index].clone()))
+            .collect();
+    }
+
+    /**
+     * Same as fuzzy_match, but an abbreviated genus ("E. coli") is first expanded, see expand_abbreviation.
+     * Every expansion ("Escherichia coli", "Entamoeba coli") is matched, and their results merged by rank,
+     * ties in the order of the genera in the dataset.
+     * Also returns the genera of the expansion, none when input is not abbreviated or no genus fits.
+     */
+    pub fn fuzzy_match_expanded(&self, matcher: &mut Matcher, input: String, limit: usize) -> (Vec<EngineInputData>, Option<Vec<String>>) {
+        let (result, genera) = self.fuzzy_match_expanded_scored(matcher, input, limit);
+        return (result.into_iter().map(|(_, item)| item).collect(), genera);
+    }
+
+    // same as fuzzy_match_expanded, along with the score of each match
+    pub fn fuzzy_match_expanded_scored(&self, matcher: &mut Matcher, input: String, limit: usize) -> (Vec<(u32, EngineInputData)>, Option<Vec<String>>) {
+        let Some((genera, queries)) = self.expand_abbreviation(&input) else {
+            return (self.fuzzy_match_scored(matcher, input, limit), None);
+        };
+
+        // an item matched by several expansions keeps its best rank
+        let mut merged: Vec<Rank> = Vec::new();
+        for query in queries {
+            for rank in self.top_k(matcher, query, limit) {
+                let index = rank.4;
+                match merged.iter_mut().find(|merged_rank| merged_rank.4 == index) {
+                    Some(merged_rank) => *merged_rank = std::cmp::min(*merged_rank, rank),
+                    None => merged.push(rank),
+                }
+            }
+        }
+        // stable sort, equal ranks keep the order of the genera
+        merged.sort();
+        merged.truncate(limit);
+
+        let result = merged.into_iter()
+            .map(|(Reverse(score), _, _, _, index)| (score, self.items[index].clone()))
+            .collect();
+        return (result, Some(genera));
+    }
+
+    /**
+     * The genera of the dataset an abbreviated genus ("E. coli") can stand for: those with that initial and a species
+     * with that epithet, in dataset order, only the first max_expansions of them so that a common epithet doesn't
+     * turn one query into hundreds. Also returns input with each of them in place of the abbreviation.
+     * None when input is not abbreviated or no genus fits.
+     */
+    pub fn expand_abbreviation(&self, input: &str) -> Option<(Vec<String>, Vec<String>)> {
+        let (initial, rest) = names::genus_abbreviation(input)?;
+        let mut genera = self.genera_of_abbreviation(initial, rest);
+        genera.truncate(self.max_expansions);
+        if genera.is_empty() {
+            return None;
+        }
+        let queries = genera.iter().map(|genus| format!("{} {}", genus, rest)).collect();
+        return Some((genera, queries));
+    }
+
+    // genera starting with initial, that have a species with the epithet rest starts with
+    fn genera_of_abbreviation(&self, initial: char, rest: &str) -> Vec<String> {
+        let epithet = rest.split_whitespace().next().unwrap_or("").to_lowercase();
+        let Some(genera) = self.genera_by_epithet.get(&epithet) else {
+            return vec![];
+        };
+        return genera.iter()
+            .filter(|genus| genus.chars().next().is_some_and(|first| first.to_uppercase().eq(initial.to_uppercase())))
+            .cloned()
+            .collect();
+    }
+
+    // rank of the limit best matches of input, best first, the index of the item is last
+    fn top_k(&self, matcher: &mut Matcher, input: String, limit: usize) -> Vec<Rank> {
+        if limit == 0 {
+            return vec![];
+        }
+        let query_authorship = to_query_authorship(&input, self.canonical);
+        let query = to_query(input, self.canonical);
+        let pattern = Pattern::parse(&query, CaseMatching::Ignore, Normalization::Never);
+
+        // worst kept match on top, so it is the one evicted when a better one comes
+        let mut top_k: BinaryHeap<Rank> = BinaryHeap::with_capacity(limit + 1);
+        for (index, haystack) in self.haystacks.iter().enumerate() {
+            let Some(score) = pattern.score(haystack.slice(..), matcher) else {
+                continue;
+            };
+            // without authorship, no tie-break, same as Nucleo
+            let rank = match query_authorship.is_empty() {
+                true => (Reverse(score), haystack.len(), index, (0, 0), index),
+                false => (
+                    Reverse(score),
+                    haystack.len(),
+                    self.homonym_groups[index],
+                    query_authorship.distance(&self.authorships[index]),
+                    index),
+            };
+            if top_k.len() < limit {
+                top_k.push(rank);
+            } else if top_k.peek().is_some_and(|worst| rank < *worst) {
+                top_k.pop();
+                top_k.push(rank);
+            }
+        }
+
+        return top_k.into_sorted_vec();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BatchMatcher;
+    use crate::io::{test_items, EngineInputData};
+
+    fn batch_matcher(strings: &[&str]) -> BatchMatcher {
+        return BatchMatcher::new(&test_items(strings), true, 10);
+    }
+
+    fn strings(matches: Vec<EngineInputData>) -> Vec<String> {
+        return matches.into_iter().map(|item| item.string).collect();
+    }
+
+    #[test]
+    fn abbreviated_genus_expanded() {
+        let batch_matcher = batch_matcher(&[
+            "Enterobacter cloacae",
+            "Escherichia coli (Migula, 1895)",
+            "Escherichia albertii",
+            "Salmonella enterica",
+        ]);
+        let mut matcher = BatchMatcher::new_matcher();
+
+        let (matches, genera) = batch_matcher.fuzzy_match_expanded(&mut matcher, "E. coli".to_owned(), 1);
+        assert_eq!(strings(matches), vec!["Escherichia coli (Migula, 1895)"]);
+        assert_eq!(genera, Some(vec!["Escherichia".to_owned()]));
+
+        let (_, genera) = batch_matcher.fuzzy_match_expanded(&mut matcher, "Escherichia coli".to_owned(), 1);
+        assert_eq!(genera, None);
+        // no genus with that initial has the epithet
+        let (_, genera) = batch_matcher.fuzzy_match_expanded(&mut matcher, "S. coli".to_owned(), 1);
+        assert_eq!(genera, None);
+    }
+
+    #[test]
+    fn capitalized_epithet_in_query() {
+        let mut matcher = BatchMatcher::new_matcher();
+        let oaks = batch_matcher(&["Quercus alba", "Quercus robur"]);
+        assert_eq!(strings(oaks.fuzzy_match(&mut matcher, "Quercus Robur".to_owned(), 1)), vec!["Quercus robur"]);
+
+        // the letter typed so far is kept
+        let pumas = batch_matcher(&["Puma yagouaroundi", "Puma concolor (Linnaeus, 1771)"]);
+        assert_eq!(strings(pumas.fuzzy_match(&mut matcher, "Puma C".to_owned(), 2)), vec!["Puma concolor (Linnaeus, 1771)"]);
+    }
+
+    #[test]
+    fn expansions_merged_by_rank() {
+        let batch_matcher = batch_matcher(&[
+            "Eb coli bus",
+            "Ea coli Smith, 1900",
+            "Ea coli (Jones, 1850)",
+        ]);
+        let mut matcher = BatchMatcher::new_matcher();
+
+        let (matches, genera) = batch_matcher.fuzzy_match_expanded(&mut matcher, "E. coli".to_owned(), 3);
+        assert_eq!(genera, Some(vec!["Eb".to_owned(), "Ea".to_owned()]));
+        // same score, the shorter canonical name first, whatever the order of the genera
+        assert_eq!(strings(matches), vec!["Ea coli Smith, 1900", "Ea coli (Jones, 1850)", "Eb coli bus"]);
+
+        // homonyms are tie-broken by the authorship of the query
+        let (matches, _) = batch_matcher.fuzzy_match_expanded(&mut matcher, "E. coli Jones".to_owned(), 3);
+        assert_eq!(strings(matches), vec!["Ea coli (Jones, 1850)", "Ea coli Smith, 1900", "Eb coli bus"]);
+    }
+
+    #[test]
+    fn expansions_capped() {
+        let matcher_items = test_items(&["Ea coli", "Eb coli", "Ec coli"]);
+        let batch_matcher = BatchMatcher::new(&matcher_items, true, 2);
+        let mut matcher = BatchMatcher::new_matcher();
+
+        // the first genera in dataset order
+        let (matches, genera) = batch_matcher.fuzzy_match_expanded(&mut matcher, "E. coli".to_owned(), 3);
+        assert_eq!(genera, Some(vec!["Ea".to_owned(), "Eb".to_owned()]));
+        assert_eq!(strings(matches), vec!["Ea coli", "Eb coli"]);
+    }
+}
@@ -0,0 +1,51 @@
+use std::time::Instant;
+
+use crate::{batch::BatchMatcher, engine::EngineWrapper, io::EngineInputData};
+
+/**
+ * Compares the two ways of matching a batch, on a single thread: the Nucleo engine used by autocomplete,
+ * and the one-shot BatchMatcher used by /fuzzy_match. queries_path is a json array of strings.
+ * Run with --bench, prints a report and exits.
+ */
+pub fn run(dataset: &[EngineInputData], queries_path: &str, limit: usize, canonical: bool, max_expansions: usize) -> Result<(), String> {
+    let content = std::fs::read_to_string(queries_path).map_err(|e| format!("Cannot read {}: {}", queries_path, e))?;
+    let queries = serde_json::from_str::<Vec<String>>(&content).map_err(|e| format!("Invalid queries file {}: {}", queries_path, e))?;
+    println!("{} items, {} queries, {} results per query", dataset.len(), queries.len(), limit);
+
+    let start = Instant::now();
+    let mut engine = EngineWrapper::new(dataset, canonical);
+    let engine_build = start.elapsed();
+    let start = Instant::now();
+    let engine_results = queries.iter()
+        .map(|query| engine.fuzzy_match(query.clone(), limit))
+        .collect::<Vec<Vec<EngineInputData>>>();
+    let engine_matching = start.elapsed();
+
+    let start = Instant::now();
+    let batch_matcher = BatchMatcher::new(dataset, canonical, max_expansions);
+    let batch_build = start.elapsed();
+    let start = Instant::now();
+    let mut matcher = BatchMatcher::new_matcher();
+    let batch_results = queries.iter()
+        .map(|query| batch_matcher.fuzzy_match(&mut matcher, query.clone(), limit))
+        .collect::<Vec<Vec<EngineInputData>>>();
+    let batch_matching = start.elapsed();
+
+    let differences = engine_results.iter().zip(&batch_results)
+        .filter(|(engine_result, batch_result)| {
+            let engine_strings = engine_result.iter().map(|item| &item.string);
+            return !engine_strings.eq(batch_result.iter().map(|item| &item.string));
+        })
+        .count();
+
+    for (name, build, matching) in [("engine", engine_build, engine_matching), ("batch matcher", batch_build, batch_matching)] {
+        println!(
+            "{:>14}: built in {:>8.1} ms, matched in {:>9.1} ms, {:>8.1} µs per query",
+            name,
+            build.as_secs_f64() * 1000.0,
+            matching.as_secs_f64() * 1000.0,
+            matching.as_secs_f64() * 1_000_000.0 / queries.len().max(1) as f64);
+    }
+    println!("{} queries with different results", differences);
+    return Ok(());
+}
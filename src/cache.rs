@@ -0,0 +1,154 @@
+use std::{cmp::Reverse, collections::{HashMap, VecDeque}, sync::Arc};
+
+use nucleo_matcher::{pattern::{CaseMatching, Normalization, Pattern}, Matcher};
+use parking_lot::Mutex;
+
+use crate::{engine::to_haystack, io::EngineInputData};
+
+pub type SharedPrefixCache = Arc<Mutex<PrefixCache>>;
+
+// negation, anchors, exact and escaped atoms, narrowing from a prefix is not sound with them
+const PATTERN_SYNTAX: [char; 5] = ['!', '^', '$', '\'', '\\'];
+
+/**
+ * Candidate sets of previous queries, shared by every request of the stateless autocomplete.
+ *
+ * Every item matching "Quercus ro" also matches "Quercus r", so a query can be answered by
+ * matching only the candidates cached for its longest cached prefix, instead of the whole dataset.
+ * Candidates are stored in rank order, so an exact hit needs no matching at all.
+ * Oldest entries are evicted first once capacity is reached.
+ * This only holds for plain fuzzy atoms: queries using nucleo's pattern syntax (see PATTERN_SYNTAX) are never cached.
+ * The candidates of "Quercus !r" exclude every name containing "r", some of which match "Quercus !ro".
+ */
+pub struct PrefixCache {
+    entries: HashMap<String, Arc<Vec<EngineInputData>>>,
+    insertion_order: VecDeque<String>,
+    capacity: usize,
+}
+
+impl PrefixCache {
+    pub fn new(capacity: usize) -> Self {
+        return PrefixCache { entries: HashMap::new(), insertion_order: VecDeque::new(), capacity };
+    }
+
+    /**
+     * Returns the longest cached prefix of query (possibly query itself) and its candidates.
+     * None for queries that can't be cached.
+     */
+    pub fn lookup(&self, query: &str) -> Option<(String, Arc<Vec<EngineInputData>>)> {
+        if !is_cacheable(query) {
+            return None;
+        }
+
+        // end of each non empty prefix, longest first
+        let prefix_ends = query.char_indices()
+            .map(|(i, c)| i + c.len_utf8())
+            .rev();
+
+        for prefix_end in prefix_ends {
+            if let Some(candidates) = self.entries.get(&query[0..prefix_end]) {
+                return Some((query[0..prefix_end].to_owned(), candidates.clone()));
+            }
+        }
+        return None;
+    }
+
+    pub fn insert(&mut self, query: String, candidates: Arc<Vec<EngineInputData>>) {
+        if self.capacity == 0 || !is_cacheable(&query) || self.entries.contains_key(&query) {
+            return;
+        }
+
+        if self.entries.len() >= self.capacity {
+            if let Some(oldest) = self.insertion_order.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.insertion_order.push_back(query.clone());
+        self.entries.insert(query, candidates);
+    }
+}
+
+fn is_cacheable(query: &str) -> bool {
+    return !query.contains(PATTERN_SYNTAX);
+}
+
+/**
+ * Matches query against the candidates of one of its prefixes.
+ * Returns every candidate still matching, in rank order.
+ * Ranked like Nucleo does, ties on score and length keep the order of the candidates, already ranked for the prefix.
+ * query is already the one the engines would match (see to_query), canonical is whether they match canonical names.
+ */
+pub fn narrow(candidates: &[EngineInputData], query: &str, canonical: bool) -> Vec<EngineInputData> {
+    let mut matcher = Matcher::new(nucleo_matcher::Config::DEFAULT);
+    let pattern = Pattern::parse(query, CaseMatching::Ignore, Normalization::Never);
+
+    let mut scored: Vec<(Reverse<u32>, usize, usize, &EngineInputData)> = Vec::new();
+    for (position, candidate) in candidates.iter().enumerate() {
+        let haystack = to_haystack(candidate, canonical);
+        if let Some(score) = pattern.score(haystack.slice(..), &mut matcher) {
+            scored.push((Reverse(score), haystack.len(), position, candidate));
+        }
+    }
+    // same order as Nucleo: best score, then shortest string, then the order of the prefix's candidates
+    scored.sort_by_key(|(score, length, position, _)| (*score, *length, *position));
+
+    return scored.into_iter().map(|(_, _, _, candidate)| candidate.clone()).collect::<Vec<EngineInputData>>();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{narrow, PrefixCache};
+    use crate::io::{test_items, EngineInputData};
+
+    fn items(strings: &[&str]) -> Arc<Vec<EngineInputData>> {
+        return Arc::new(test_items(strings));
+    }
+
+    #[test]
+    fn lookup_longest_prefix() {
+        let mut cache = PrefixCache::new(10);
+        cache.insert("Quercus".to_owned(), items(&["Quercus robur", "Quercus petraea"]));
+        cache.insert("Quercus r".to_owned(), items(&["Quercus robur"]));
+
+        let (prefix, candidates) = cache.lookup("Quercus ro").unwrap();
+        assert_eq!(prefix, "Quercus r");
+        assert_eq!(candidates.len(), 1);
+        assert!(cache.lookup("Puma").is_none());
+    }
+
+    #[test]
+    fn pattern_syntax_is_not_cached() {
+        let mut cache = PrefixCache::new(10);
+        // the candidates of "Quercus !r" lack "Quercus rubra", which matches "Quercus !ro"
+        cache.insert("Quercus !r".to_owned(), items(&["Quercus petraea"]));
+        assert!(cache.lookup("Quercus !r").is_none());
+
+        cache.insert("Quercus".to_owned(), items(&["Quercus rubra", "Quercus robur", "Quercus petraea"]));
+        assert!(cache.lookup("Quercus !ro").is_none());
+        assert!(cache.lookup("^Quercus").is_none());
+        assert!(cache.lookup("Quercus$").is_none());
+        assert!(cache.lookup("'Quercus").is_none());
+        assert!(cache.lookup("Quercus\\ r").is_none());
+    }
+
+    #[test]
+    fn oldest_entry_evicted() {
+        let mut cache = PrefixCache::new(1);
+        cache.insert("Quercus".to_owned(), items(&["Quercus robur"]));
+        cache.insert("Puma".to_owned(), items(&["Puma concolor"]));
+        assert!(cache.lookup("Quercus").is_none());
+        assert!(cache.lookup("Puma").is_some());
+    }
+
+    #[test]
+    fn narrow_ranks_like_nucleo() {
+        // same score for "Quercus ro", the shorter string first, whatever the order of the prefix's candidates
+        let candidates = items(&["Quercus robur subsp. robur", "Quercus robur", "Quercus petraea"]);
+        let narrowed = narrow(&candidates, "Quercus ro", false).into_iter()
+            .map(|item| item.string)
+            .collect::<Vec<String>>();
+        assert_eq!(narrowed, vec!["Quercus robur", "Quercus robur subsp. robur"]);
+    }
+}
@@ -0,0 +1,496 @@
+use std::{fs::File, str::FromStr};
+
+use axum::http::{HeaderName, HeaderValue, Method};
+
+use clap::Parser;
+use serde::Deserialize;
+
+/*
+    Configuration is resolved in this order, each level overriding the previous one:
+    ServerConfig::default(), the toml file given with --config, environment variables, command line flags.
+    Clap takes care of the last two, as each flag can also be given by its env variable.
+ */
+#[derive(Parser, Debug)]
+#[command(version)]
+pub struct Args {
+    // toml file with any of the ServerConfig fields
+    #[arg(long = "config", env = "FTS_CONFIG", value_parser = valid_file)]
+    config_file: Option<String>,
+
+    #[arg(short= 'i', long = "input", env = "FTS_INPUT", value_parser = valid_file)]
+    json_input: Option<String>,
+
+    // json array of query strings: compare batch matching with the engine on them, then exit
+    #[arg(long, value_parser = valid_file)]
+    pub bench: Option<String>,
+
+    #[arg(long, env = "FTS_TLS_CERT_PATH", value_parser = valid_file)]
+    tls_cert_path: Option<String>,
+    #[arg(long, env = "FTS_TLS_KEY_PATH", value_parser = valid_file)]
+    tls_key_path: Option<String>,
+    #[arg(long, env = "FTS_TLS_SELF_SIGNED", num_args = 0..=1, default_missing_value = "true")]
+    tls_self_signed: Option<bool>,
+
+    #[arg(long, env = "FTS_API_KEYS_PATH", value_parser = valid_file)]
+    api_keys_path: Option<String>,
+
+    #[arg(long, env = "FTS_CLIENT_REQUESTS_PER_MINUTE")]
+    client_requests_per_minute: Option<u32>,
+    #[arg(long, env = "FTS_CLIENT_BURST")]
+    client_burst: Option<u32>,
+    #[arg(long, env = "FTS_MAX_ENGINES_PER_CLIENT")]
+    max_engines_per_client: Option<usize>,
+    #[arg(long, env = "FTS_TRUST_FORWARDED_FOR", num_args = 0..=1, default_missing_value = "true")]
+    trust_forwarded_for: Option<bool>,
+
+    #[arg(long, env = "FTS_BIND_ADDRESS")]
+    bind_address: Option<String>,
+    #[arg(long, env = "FTS_PORT")]
+    port: Option<u16>,
+
+    #[arg(long, env = "FTS_AUTOCOMPLETE_POOL_MAX_SIZE")]
+    autocomplete_pool_max_size: Option<usize>,
+    #[arg(long, env = "FTS_AUTOCOMPLETE_POOL_MIN_SIZE")]
+    autocomplete_pool_min_size: Option<usize>,
+    #[arg(long, env = "FTS_SESSION_EXPIRY_DELAY")]
+    session_expiry_delay: Option<u64>,
+    #[arg(long, env = "FTS_ENGINE_RETURNED_ADDITIONAL_DELAY")]
+    engine_returned_additional_delay: Option<u64>,
+
+    #[arg(long, env = "FTS_GP_POOL_MAX_SIZE")]
+    gp_pool_max_size: Option<usize>,
+    #[arg(long, env = "FTS_GP_POOL_MIN_SIZE")]
+    gp_pool_min_size: Option<usize>,
+    #[arg(long, env = "FTS_BATCH_WORKERS")]
+    batch_workers: Option<usize>,
+    #[arg(long, env = "FTS_FUZZY_MATCH_PARALLELISM")]
+    fuzzy_match_parallelism: Option<usize>,
+    #[arg(long, env = "FTS_ENGINE_WAIT_TIMEOUT")]
+    engine_wait_timeout: Option<u64>,
+
+    // serve autocomplete from the general purpose pool, without sessions
+    #[arg(long, env = "FTS_STATELESS_AUTOCOMPLETE", num_args = 0..=1, default_missing_value = "true")]
+    stateless_autocomplete: Option<bool>,
+    #[arg(long, env = "FTS_PREFIX_CACHE_CAPACITY")]
+    prefix_cache_capacity: Option<usize>,
+    #[arg(long, env = "FTS_PREFIX_CACHE_MAX_CANDIDATES")]
+    prefix_cache_max_candidates: Option<usize>,
+
+    #[arg(long, env = "FTS_MAX_FIELDS_PER_SESSION")]
+    max_fields_per_session: Option<usize>,
+
+    #[arg(long, env = "FTS_AUTOCOMPLETE_RESULT_LIMIT")]
+    autocomplete_result_limit: Option<usize>,
+    #[arg(long, env = "FTS_FUZZY_MATCH_RESULT_LIMIT")]
+    fuzzy_match_result_limit: Option<usize>,
+
+    #[arg(long, env = "FTS_MAX_BODY_SIZE")]
+    max_body_size: Option<usize>,
+    #[arg(long, env = "FTS_MAX_BATCH_SIZE")]
+    max_batch_size: Option<usize>,
+    #[arg(long, env = "FTS_MAX_STRING_LENGTH")]
+    max_string_length: Option<usize>,
+
+    #[arg(long, env = "FTS_MATCH_ON")]
+    match_on: Option<String>,
+    #[arg(long, env = "FTS_MAX_ABBREVIATION_EXPANSIONS")]
+    max_abbreviation_expansions: Option<usize>,
+
+    #[arg(long, env = "FTS_JOBS_DIR")]
+    jobs_dir: Option<String>,
+    #[arg(long, env = "FTS_JOB_RETENTION")]
+    job_retention: Option<u64>,
+    #[arg(long, env = "FTS_MAX_JOB_STRINGS")]
+    max_job_strings: Option<usize>,
+    #[arg(long, env = "FTS_MAX_JOB_BODY_SIZE")]
+    max_job_body_size: Option<usize>,
+
+    // comma separated lists
+    #[arg(long, env = "FTS_NORMALIZATION", value_delimiter = ',')]
+    normalization: Option<Vec<String>>,
+    #[arg(long, env = "FTS_CORS_ALLOWED_ORIGINS", value_delimiter = ',')]
+    cors_allowed_origins: Option<Vec<String>>,
+    #[arg(long, env = "FTS_CORS_ALLOWED_METHODS", value_delimiter = ',')]
+    cors_allowed_methods: Option<Vec<String>>,
+    #[arg(long, env = "FTS_CORS_ALLOWED_HEADERS", value_delimiter = ',')]
+    cors_allowed_headers: Option<Vec<String>>,
+    #[arg(long, env = "FTS_CORS_ALLOW_CREDENTIALS", num_args = 0..=1, default_missing_value = "true")]
+    cors_allow_credentials: Option<bool>,
+    #[arg(long, env = "FTS_COOKIE_SAME_SITE")]
+    cookie_same_site: Option<String>,
+
+    #[arg(long, env = "FTS_LOG_LEVEL")]
+    log_level: Option<String>,
+    #[arg(long, env = "FTS_LOG_FORMAT")]
+    log_format: Option<String>,
+}
+
+fn valid_file(s: &str) -> Result<String, String> {
+    // simply check if string is an openable file
+    match File::open(s) {
+        Ok(_) => Ok(s.to_owned()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind_address: String,
+    pub port: u16,
+    // json file of EngineInputData
+    pub dataset_path: String,
+
+    /*
+        HTTPS, with a PEM certificate chain and private key. Session cookies are only made secure when it is on,
+        browsers would not send them back over plain HTTP otherwise.
+        tls_self_signed generates a throwaway certificate for localhost instead, for local testing only.
+     */
+    pub tls_cert_path: Option<String>,
+    pub tls_key_path: Option<String>,
+    pub tls_self_signed: bool,
+
+    // toml file of API keys, see api_keys.example.toml. Without it, every route is open to everyone
+    pub api_keys_path: Option<String>,
+
+    /*
+        Per client limits, a client being its API key if it has one, its IP address otherwise.
+        Token bucket of client_burst requests, refilled at client_requests_per_minute. 0 requests per minute turns it off.
+        Keys with their own requests_per_minute are only limited by it.
+     */
+    pub client_requests_per_minute: u32,
+    pub client_burst: u32,
+    // autocomplete engines a client can hold at once, across its sessions, fields and websockets
+    pub max_engines_per_client: usize,
+    // take the client IP from the X-Forwarded-For header, only when behind a reverse proxy that sets it
+    pub trust_forwarded_for: bool,
+
+    // engine pool specificly for autocomplete
+    pub autocomplete_pool_max_size: usize,
+    pub autocomplete_pool_min_size: usize,
+    pub session_expiry_delay: u64, // in seconds
+    /*
+        We absolutely want to avoid valid sessions trying to use expired engines that have been returned back to the pool.
+        So the delay for returning en engine should be more than the expiry of a session.
+        On the other hand it is ok if a usable engine sits unused because its session has expired.
+        Total expiry of an engine will be session_expiry_delay + engine_returned_additional_delay.
+     */
+    pub engine_returned_additional_delay: u64, // in seconds
+
+    // general purpose engine pool, for stateless autocomplete
+    pub gp_pool_max_size: usize,
+    pub gp_pool_min_size: usize,
+    /*
+        /fuzzy_match batches don't use engines, but a matcher shared by everyone. batch_workers is the number of
+        chunks matched at once, across all requests. A batch can be split across at most fuzzy_match_parallelism
+        of them, so one batch can't take them all.
+     */
+    pub batch_workers: usize,
+    pub fuzzy_match_parallelism: usize,
+
+    // how long a request waits for an engine of an exhausted pool before giving up, in seconds
+    pub engine_wait_timeout: u64,
+
+    /*
+        Stateless autocomplete: no engine is attributed to sessions, queries are served from the general purpose pool
+        and a prefix cache shared by everyone. For deployments without session affinity.
+     */
+    pub stateless_autocomplete: bool,
+    pub prefix_cache_capacity: usize, // number of queries kept
+    pub prefix_cache_max_candidates: usize, // queries matching more items than this are not cached
+
+    // each autocomplete field of a session gets its own engine, up to this number of fields
+    pub max_fields_per_session: usize,
+
+    // number of results returned by autocomplete, and at most by fuzzy_match for each string (larger n_first_results are refused)
+    pub autocomplete_result_limit: usize,
+    pub fuzzy_match_result_limit: usize,
+
+    /*
+        Requests over these are refused before any work is done.
+        max_body_size is in bytes, max_batch_size is the number of strings of /fuzzy_match and /exact_match,
+        max_string_length is in characters, for each of them and for autocomplete queries.
+     */
+    pub max_body_size: usize,
+    pub max_batch_size: usize,
+    pub max_string_length: usize,
+
+    /*
+        Steps applied to strings for the normalized exact index, used by /match and /exact_match with normalize.
+        Any of "trim", "collapse_whitespace", "case_fold", "deunicode", "strip_trailing_punctuation",
+        always applied in the order given in normalize::Normalizer.
+     */
+    pub normalization: Vec<String>,
+
+    /*
+        What fuzzy matching compares, in every engine and in batches: "string" as given in the dataset and in queries,
+        or "canonical", the scientific name without authorship, rank markers nor hybrid signs (see names::parse).
+        With "canonical", homonyms are ranked by how close their authorship is to the one of the query.
+     */
+    pub match_on: String,
+    // an abbreviated genus ("E. coli") is expanded to at most this number of genera, the first ones in the dataset
+    pub max_abbreviation_expansions: usize,
+
+    /*
+        Background jobs, for batches too long for a single request. Each job is persisted in a directory of jobs_dir,
+        so unfinished ones are resumed after a restart. Finished jobs are deleted job_retention seconds later.
+        max_job_strings and max_job_body_size replace max_batch_size and max_body_size for job uploads.
+     */
+    pub jobs_dir: String,
+    pub job_retention: u64,
+    pub max_job_strings: usize,
+    pub max_job_body_size: usize,
+
+    /*
+        CORS, for autocomplete widgets served from another origin. No origin means CORS is off.
+        Credentials let browsers send the session cookie along, they can't be combined with the "*" origin.
+        For the cookie to be sent cross-site (not only cross-origin), cookie_same_site must be "none",
+        which browsers only accept on secure cookies.
+     */
+    pub cors_allowed_origins: Vec<String>,
+    pub cors_allowed_methods: Vec<String>,
+    pub cors_allowed_headers: Vec<String>,
+    pub cors_allow_credentials: bool,
+    // "strict", "lax" or "none"
+    pub cookie_same_site: String,
+
+    // any tracing filter directive, e.g. "info" or "fuzzy_taxo_search=debug"
+    pub log_level: String,
+    // "text" or "json"
+    pub log_format: String,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind_address: "0.0.0.0".to_owned(),
+            port: 3000,
+            dataset_path: String::new(),
+            tls_cert_path: None,
+            tls_key_path: None,
+            tls_self_signed: false,
+            api_keys_path: None,
+            client_requests_per_minute: 600,
+            client_burst: 60,
+            max_engines_per_client: 4,
+            trust_forwarded_for: false,
+            autocomplete_pool_max_size: 10,
+            autocomplete_pool_min_size: 2,
+            session_expiry_delay: 10,
+            engine_returned_additional_delay: 2,
+            gp_pool_max_size: 10,
+            gp_pool_min_size: 2,
+            batch_workers: std::thread::available_parallelism().map(|cores| cores.get()).unwrap_or(4),
+            fuzzy_match_parallelism: 4,
+            engine_wait_timeout: 5,
+            stateless_autocomplete: false,
+            prefix_cache_capacity: 1000,
+            prefix_cache_max_candidates: 10000,
+            max_fields_per_session: 4,
+            autocomplete_result_limit: 10,
+            fuzzy_match_result_limit: 10,
+            max_body_size: 2 * 1024 * 1024,
+            max_batch_size: 10_000,
+            max_string_length: 500,
+            normalization: crate::normalize::NORMALIZATION_STEPS.iter().map(|step| step.to_string()).collect(),
+            match_on: "canonical".to_owned(),
+            max_abbreviation_expansions: 10,
+            jobs_dir: "jobs".to_owned(),
+            job_retention: 24 * 60 * 60,
+            max_job_strings: 1_000_000,
+            max_job_body_size: 256 * 1024 * 1024,
+            cors_allowed_origins: vec![],
+            cors_allowed_methods: vec!["GET".to_owned(), "POST".to_owned()],
+            cors_allowed_headers: vec!["content-type".to_owned(), "x-session-token".to_owned(), "x-request-id".to_owned(), "x-api-key".to_owned()],
+            cors_allow_credentials: true,
+            cookie_same_site: "strict".to_owned(),
+            log_level: "info".to_owned(),
+            log_format: "text".to_owned(),
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn get_engine_expiry(&self) -> u64 {
+        return self.session_expiry_delay + self.engine_returned_additional_delay;
+    }
+
+    pub fn is_tls_enabled(&self) -> bool {
+        return self.tls_self_signed || self.tls_cert_path.is_some();
+    }
+
+    pub fn is_match_on_canonical(&self) -> bool {
+        return self.match_on == "canonical";
+    }
+
+    pub fn get_bind_address(&self) -> String {
+        return format!("{}:{}", self.bind_address, self.port);
+    }
+
+    /**
+     * Builds the configuration from the config file, env variables and command line, then validates it.
+     */
+    pub fn load(args: Args) -> Result<Self, String> {
+        let mut config = match &args.config_file {
+            Some(config_file) => {
+                let content = std::fs::read_to_string(config_file).map_err(|e| e.to_string())?;
+                toml::from_str::<ServerConfig>(&content).map_err(|e| format!("Invalid config file {}: {}", config_file, e))?
+            },
+            None => ServerConfig::default(),
+        };
+
+        config.apply_args(args);
+        config.validate()?;
+        return Ok(config);
+    }
+
+    fn apply_args(&mut self, args: Args) {
+        if let Some(v) = args.json_input { self.dataset_path = v; }
+        if let Some(v) = args.tls_cert_path { self.tls_cert_path = Some(v); }
+        if let Some(v) = args.tls_key_path { self.tls_key_path = Some(v); }
+        if let Some(v) = args.tls_self_signed { self.tls_self_signed = v; }
+        if let Some(v) = args.api_keys_path { self.api_keys_path = Some(v); }
+        if let Some(v) = args.client_requests_per_minute { self.client_requests_per_minute = v; }
+        if let Some(v) = args.client_burst { self.client_burst = v; }
+        if let Some(v) = args.max_engines_per_client { self.max_engines_per_client = v; }
+        if let Some(v) = args.trust_forwarded_for { self.trust_forwarded_for = v; }
+        if let Some(v) = args.bind_address { self.bind_address = v; }
+        if let Some(v) = args.port { self.port = v; }
+        if let Some(v) = args.autocomplete_pool_max_size { self.autocomplete_pool_max_size = v; }
+        if let Some(v) = args.autocomplete_pool_min_size { self.autocomplete_pool_min_size = v; }
+        if let Some(v) = args.session_expiry_delay { self.session_expiry_delay = v; }
+        if let Some(v) = args.engine_returned_additional_delay { self.engine_returned_additional_delay = v; }
+        if let Some(v) = args.gp_pool_max_size { self.gp_pool_max_size = v; }
+        if let Some(v) = args.gp_pool_min_size { self.gp_pool_min_size = v; }
+        if let Some(v) = args.batch_workers { self.batch_workers = v; }
+        if let Some(v) = args.fuzzy_match_parallelism { self.fuzzy_match_parallelism = v; }
+        if let Some(v) = args.engine_wait_timeout { self.engine_wait_timeout = v; }
+        if let Some(v) = args.stateless_autocomplete { self.stateless_autocomplete = v; }
+        if let Some(v) = args.prefix_cache_capacity { self.prefix_cache_capacity = v; }
+        if let Some(v) = args.prefix_cache_max_candidates { self.prefix_cache_max_candidates = v; }
+        if let Some(v) = args.max_fields_per_session { self.max_fields_per_session = v; }
+        if let Some(v) = args.autocomplete_result_limit { self.autocomplete_result_limit = v; }
+        if let Some(v) = args.fuzzy_match_result_limit { self.fuzzy_match_result_limit = v; }
+        if let Some(v) = args.max_body_size { self.max_body_size = v; }
+        if let Some(v) = args.max_batch_size { self.max_batch_size = v; }
+        if let Some(v) = args.max_string_length { self.max_string_length = v; }
+        if let Some(v) = args.normalization { self.normalization = v; }
+        if let Some(v) = args.match_on { self.match_on = v; }
+        if let Some(v) = args.max_abbreviation_expansions { self.max_abbreviation_expansions = v; }
+        if let Some(v) = args.jobs_dir { self.jobs_dir = v; }
+        if let Some(v) = args.job_retention { self.job_retention = v; }
+        if let Some(v) = args.max_job_strings { self.max_job_strings = v; }
+        if let Some(v) = args.max_job_body_size { self.max_job_body_size = v; }
+        if let Some(v) = args.cors_allowed_origins { self.cors_allowed_origins = v; }
+        if let Some(v) = args.cors_allowed_methods { self.cors_allowed_methods = v; }
+        if let Some(v) = args.cors_allowed_headers { self.cors_allowed_headers = v; }
+        if let Some(v) = args.cors_allow_credentials { self.cors_allow_credentials = v; }
+        if let Some(v) = args.cookie_same_site { self.cookie_same_site = v; }
+        if let Some(v) = args.log_level { self.log_level = v; }
+        if let Some(v) = args.log_format { self.log_format = v; }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.dataset_path.is_empty() {
+            return Err("No dataset given, use --input or dataset_path in the config file".to_owned());
+        }
+        valid_file(&self.dataset_path).map_err(|e| format!("Cannot open dataset {}: {}", self.dataset_path, e))?;
+
+        match (&self.tls_cert_path, &self.tls_key_path) {
+            (Some(_), None) | (None, Some(_)) => {
+                return Err("tls_cert_path and tls_key_path must be given together".to_owned());
+            },
+            (Some(_), Some(_)) if self.tls_self_signed => {
+                return Err("tls_self_signed can't be used with tls_cert_path and tls_key_path".to_owned());
+            },
+            _ => {},
+        }
+        for path in [&self.tls_cert_path, &self.tls_key_path].into_iter().flatten() {
+            valid_file(path).map_err(|e| format!("Cannot open {}: {}", path, e))?;
+        }
+
+        if let Some(api_keys_path) = &self.api_keys_path {
+            valid_file(api_keys_path).map_err(|e| format!("Cannot open {}: {}", api_keys_path, e))?;
+        }
+
+        if self.client_requests_per_minute > 0 && self.client_burst == 0 {
+            return Err("client_burst must be > 0 when client_requests_per_minute is set".to_owned());
+        }
+        if self.max_engines_per_client == 0 {
+            return Err("max_engines_per_client must be > 0".to_owned());
+        }
+
+        if self.engine_returned_additional_delay == 0 {
+            // see comment on the field, a session must never outlive its engine
+            return Err("engine_returned_additional_delay must be > 0, so that engines expire after their session".to_owned());
+        }
+        if self.session_expiry_delay == 0 {
+            return Err("session_expiry_delay must be > 0".to_owned());
+        }
+
+        for (pool, min_size, max_size) in [
+            ("autocomplete", self.autocomplete_pool_min_size, self.autocomplete_pool_max_size),
+            ("gp", self.gp_pool_min_size, self.gp_pool_max_size)] {
+            if min_size == 0 {
+                // engines are only created at startup, an empty pool would never serve anything
+                return Err(format!("{}_pool_min_size must be > 0", pool));
+            }
+            if min_size > max_size {
+                return Err(format!("{}_pool_min_size ({}) must not exceed {}_pool_max_size ({})", pool, min_size, pool, max_size));
+            }
+        }
+
+        if self.batch_workers == 0 || self.fuzzy_match_parallelism == 0 {
+            return Err("batch_workers and fuzzy_match_parallelism must be > 0".to_owned());
+        }
+        if self.max_fields_per_session == 0 {
+            return Err("max_fields_per_session must be > 0".to_owned());
+        }
+        if self.autocomplete_result_limit == 0 || self.fuzzy_match_result_limit == 0 {
+            return Err("autocomplete_result_limit and fuzzy_match_result_limit must be > 0".to_owned());
+        }
+        if self.max_body_size == 0 || self.max_batch_size == 0 || self.max_string_length == 0 {
+            return Err("max_body_size, max_batch_size and max_string_length must be > 0".to_owned());
+        }
+        if let Some(step) = self.normalization.iter().find(|step| !crate::normalize::NORMALIZATION_STEPS.contains(&step.as_str())) {
+            return Err(format!("Unknown normalization step {:?}, steps are {:?}", step, crate::normalize::NORMALIZATION_STEPS));
+        }
+        if self.match_on != "string" && self.match_on != "canonical" {
+            return Err(format!("match_on must be \"string\" or \"canonical\", not {:?}", self.match_on));
+        }
+        if self.max_abbreviation_expansions == 0 {
+            return Err("max_abbreviation_expansions must be > 0".to_owned());
+        }
+        if self.jobs_dir.is_empty() {
+            return Err("jobs_dir must not be empty".to_owned());
+        }
+        if self.max_job_strings == 0 || self.max_job_body_size == 0 {
+            return Err("max_job_strings and max_job_body_size must be > 0".to_owned());
+        }
+        if self.cors_allow_credentials && self.cors_allowed_origins.iter().any(|origin| origin == "*") {
+            return Err("cors_allowed_origins can't contain \"*\" when cors_allow_credentials is true".to_owned());
+        }
+        for origin in self.cors_allowed_origins.iter().filter(|origin| *origin != "*") {
+            HeaderValue::from_str(origin).map_err(|_| format!("Invalid CORS origin {:?}", origin))?;
+        }
+        for method in &self.cors_allowed_methods {
+            Method::from_str(method).map_err(|_| format!("Invalid CORS method {:?}", method))?;
+        }
+        for header in &self.cors_allowed_headers {
+            HeaderName::from_str(header).map_err(|_| format!("Invalid CORS header {:?}", header))?;
+        }
+        if !["strict", "lax", "none"].contains(&self.cookie_same_site.as_str()) {
+            return Err(format!("cookie_same_site must be \"strict\", \"lax\" or \"none\", not {:?}", self.cookie_same_site));
+        }
+        if self.cookie_same_site == "none" && !self.is_tls_enabled() {
+            // browsers drop SameSite=None cookies that are not secure
+            return Err("cookie_same_site \"none\" requires TLS".to_owned());
+        }
+
+        if self.log_format != "text" && self.log_format != "json" {
+            return Err(format!("log_format must be \"text\" or \"json\", not {:?}", self.log_format));
+        }
+
+        return Ok(());
+    }
+}
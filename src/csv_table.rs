@@ -0,0 +1,92 @@
+use axum::http::{header, HeaderMap};
+
+/**
+ * A whole CSV or TSV file, with its header row. Rows are kept as they are, to be written back with extra columns.
+ */
+pub struct CsvTable {
+    pub delimiter: u8,
+    pub headers: Vec<String>,
+    pub rows: Vec<Vec<String>>,
+}
+
+impl CsvTable {
+    pub fn parse(content: &[u8], delimiter: u8) -> Result<Self, String> {
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(delimiter)
+            // rows shorter or longer than the header are kept as they are
+            .flexible(true)
+            .from_reader(content);
+
+        let headers = reader.headers().map_err(|e| e.to_string())?
+            .iter()
+            .map(|header| header.to_owned())
+            .collect::<Vec<String>>();
+        let mut rows = Vec::new();
+        for record in reader.records() {
+            let record = record.map_err(|e| e.to_string())?;
+            rows.push(record.iter().map(|field| field.to_owned()).collect::<Vec<String>>());
+        }
+
+        return Ok(CsvTable { delimiter, headers, rows });
+    }
+
+    // index of the column with that header, the first column when none is given
+    pub fn column_index(&self, column: Option<&str>) -> Result<usize, String> {
+        let Some(column) = column else {
+            return match self.headers.is_empty() {
+                true => Err("The file has no column".to_owned()),
+                false => Ok(0),
+            };
+        };
+        return self.headers.iter()
+            .position(|header| header == column)
+            .ok_or_else(|| format!("No column named {:?}, columns are {:?}", column, self.headers));
+    }
+
+    /**
+     * Writes the table back with the same delimiter, and extra columns appended to each row.
+     * Rows shorter than the header are padded, so the extra columns stay aligned.
+     */
+    pub fn write_with_columns(&self, extra_headers: &[String], extra_values: &[Vec<String>]) -> Result<Vec<u8>, csv::Error> {
+        let mut writer = csv::WriterBuilder::new()
+            .delimiter(self.delimiter)
+            .flexible(true)
+            .from_writer(Vec::new());
+
+        writer.write_record(self.headers.iter().chain(extra_headers))?;
+        for (row, extra) in self.rows.iter().zip(extra_values) {
+            let padding = self.headers.len().saturating_sub(row.len());
+            let padded = row.iter().cloned().chain(std::iter::repeat_n(String::new(), padding));
+            writer.write_record(padded.chain(extra.iter().cloned()))?;
+        }
+        return writer.into_inner().map_err(|e| e.into_error().into());
+    }
+
+    // values of a column, empty for rows that are too short
+    pub fn column_values(&self, index: usize) -> Vec<String> {
+        return self.rows.iter()
+            .map(|row| row.get(index).cloned().unwrap_or_default())
+            .collect();
+    }
+}
+
+// content type of a CSV or TSV file
+pub fn content_type(delimiter: u8) -> &'static str {
+    return match delimiter {
+        b'\t' => "text/tab-separated-values",
+        _ => "text/csv",
+    };
+}
+
+/**
+ * Delimiter for a CSV or TSV content type, None for other content types.
+ */
+pub fn delimiter_from_headers(headers: &HeaderMap) -> Option<u8> {
+    let content_type = headers.get(header::CONTENT_TYPE)?.to_str().ok()?;
+    let mime = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    return match mime.as_str() {
+        "text/csv" => Some(b','),
+        "text/tab-separated-values" => Some(b'\t'),
+        _ => None,
+    };
+}
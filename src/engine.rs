@@ -1,15 +1,15 @@
-use std::{sync::{Arc, Mutex}, borrow::BorrowMut, collections::HashMap};
+use std::{sync::{atomic::{AtomicBool, AtomicUsize, Ordering}, Arc}, borrow::BorrowMut, cmp::Reverse, collections::HashMap};
 
-use deadpool::unmanaged;
+use deadpool::unmanaged::{self, PoolError};
 use futures_delay_queue::{delay_queue, DelayHandle, DelayQueue};
 use futures_intrusive::{channel::shared::GenericReceiver, buffer::GrowingHeapBuf};
 use nucleo::Nucleo;
-use nucleo_matcher::Utf32String;
-use parking_lot::RawMutex;
+use nucleo_matcher::{pattern::{CaseMatching, Normalization, Pattern}, Matcher, Utf32String};
+use parking_lot::{Mutex, RawMutex};
 use tokio::sync::Mutex as tok_Mutex;
 use uuid::Uuid;
 
-use crate::io::EngineInputData;
+use crate::{io::EngineInputData, names::{self, Authorship}};
 
 /* 
 behaves like an Arc<Mutex> (doc says: This struct can be cloned and transferred 
@@ -18,11 +18,20 @@ across thread boundaries and uses reference counting for its internal state.)
 pub type EnginePool = unmanaged::Pool<EngineWrapper>;
 pub type UsedEngineMap = Arc<tok_Mutex<HashMap<Uuid, (EngineWrapper, DelayHandle)>>>;
 pub type DelayQRx = GenericReceiver<RawMutex, Uuid, GrowingHeapBuf<Uuid>>;
+/*
+    Engines owned by a pool, whether they are in it or taken out of it (attributed to a session, or being used
+    by a handler). Changes only when engines of the pool are created or dropped, see EngineWrapper::owned_by.
+ */
+pub type EngineCount = Arc<AtomicUsize>;
 
 #[derive()]
 pub struct EngineWrapper {
     engine: Nucleo<EngineInputData>, // is arc mutex really needed here ?
     prev_search_str: String,
+    // strings and queries are matched on their canonical name, see names::canonical
+    canonical: bool,
+    // the count of the pool this engine belongs to, if any
+    owner: Option<EngineCount>,
 }
 
 impl EngineWrapper {
@@ -35,78 +44,295 @@ impl EngineWrapper {
         );
     }
 
-    pub fn new(db_string: &Vec<EngineInputData>) -> Self {
-        println!("Create new engine");
+    pub fn new(db_string: &[EngineInputData], canonical: bool) -> Self {
+        tracing::debug!("Create new engine");
         let engine = EngineWrapper::init_engine();
         
         // populate the search set
         let injector = engine.injector();
         //species_name_set.into_iter().for_each(|species_name| { inject.push(species_name, |_, _| {}); });
-        for item in db_string.into_iter() {
+        for item in db_string.iter() {
             injector.push(item.clone(), |input_data, buffer| {
-                buffer[0] = Utf32String::Ascii(input_data.string.clone().into());
+                buffer[0] = to_haystack(input_data, canonical);
             });
         }
 
-        return EngineWrapper { engine: engine, prev_search_str: String::new() };
+        return EngineWrapper { engine, prev_search_str: String::new(), canonical, owner: None };
     }
 
-    pub fn fuzzy_match(&mut self, input: String) -> Vec<EngineInputData> {
+    // counted in count until dropped
+    pub fn owned_by(mut self, count: &EngineCount) -> Self {
+        count.fetch_add(1, Ordering::Relaxed);
+        self.owner = Some(count.clone());
+        return self;
+    }
+
+    /**
+     * The limit best matches of input. When matching canonical names, homonyms are ranked
+     * by how close their authorship is to the one of input (see names::tie_break).
+     */
+    pub fn fuzzy_match(&mut self, input: String, limit: usize) -> Vec<EngineInputData> {
+        let query_authorship = to_query_authorship(&input, self.canonical);
+        self.run_search(input, None);
+
+        //println!("Nucleo status after tick {:?}", status);
+        //println!("result count {:?}", self.nucleo_matcher.snapshot().matched_item_count());
+        return self.ranked_results(&query_authorship, limit);
+    }
+
+    /**
+     * Same as fuzzy_match, for the expansions of an abbreviated genus (see BatchMatcher::expand_abbreviation),
+     * their results merged with merge_expansions. A single query is matched like fuzzy_match.
+     */
+    pub fn fuzzy_match_expanded(&mut self, queries: Vec<String>, limit: usize) -> Vec<EngineInputData> {
+        // never cancelled
+        return self.match_queries(queries, limit, None).unwrap_or_default();
+    }
+
+    /**
+     * Same as fuzzy_match_expanded, but gives up as soon as cancel is set, returning None.
+     */
+    pub fn fuzzy_match_cancellable(&mut self, queries: Vec<String>, limit: usize, cancel: &AtomicBool) -> Option<Vec<EngineInputData>> {
+        return self.match_queries(queries, limit, Some(cancel));
+    }
+
+    fn match_queries(&mut self, queries: Vec<String>, limit: usize, cancel: Option<&AtomicBool>) -> Option<Vec<EngineInputData>> {
+        let mut results = Vec::with_capacity(queries.len());
+        for query in queries {
+            let query_authorship = to_query_authorship(&query, self.canonical);
+            if !self.run_search(query.clone(), cancel) {
+                return None;
+            }
+            results.push((query, self.ranked_results(&query_authorship, limit)));
+        }
+        return Some(merge_expansions(results, self.canonical, limit));
+    }
+
+    /**
+     * Same as fuzzy_match, but also returns every matched item, in rank order,
+     * if there are no more than max_candidates of them. Candidates are not tie-broken, they don't depend on authorship.
+     */
+    pub fn fuzzy_match_candidates(&mut self, input: String, limit: usize, max_candidates: usize) -> (Vec<EngineInputData>, Option<Vec<EngineInputData>>) {
+        let query_authorship = to_query_authorship(&input, self.canonical);
+        self.run_search(input, None);
+
+        let result = self.ranked_results(&query_authorship, limit);
+        let snapshot = self.engine.snapshot();
+        let matched_count = snapshot.matched_item_count();
+
+        if matched_count as usize > max_candidates {
+            return (result, None);
+        }
+
+        let candidates = snapshot.matched_items(..)
+            .map(|item| item.data.clone() )
+            .collect::<Vec<EngineInputData>>();
+        return (result, Some(candidates));
+    }
+
+    // results of the last search, homonyms being the items with the same haystack
+    fn ranked_results(&self, query_authorship: &Authorship, limit: usize) -> Vec<EngineInputData> {
+        let ranked = self.engine.snapshot().matched_items(..)
+            .map(|item| (&item.matcher_columns[0], item.data));
+        return names::tie_break(query_authorship, ranked, limit);
+    }
+
+    /*
+        Reparse the pattern and make matcher work until it finishes, results are then in the snapshot.
+        Returns false if cancel was set before that, the snapshot is then incomplete.
+     */
+    fn run_search(&mut self, input: String, cancel: Option<&AtomicBool>) -> bool {
         let nucleo_matcher = self.engine.borrow_mut();
 
         //println!("Original input: {:?} is ascii ? {}", input, input.is_ascii());
-        let ascii_input = if input.is_ascii() {
-            input
-        } else {
-            deunicode::deunicode(input.as_str())
-        };
+        let ascii_input = to_query(input, self.canonical);
         //println!("Unidecoded: {:?}", ascii_input);
 
         // test if current input is an extension of previous input
-        let is_string_extension = self.prev_search_str.len() > 1 && ascii_input[0..ascii_input.len()-1] == self.prev_search_str;
+        let is_string_extension = self.prev_search_str.len() > 1 && !ascii_input.is_empty() && ascii_input[0..ascii_input.len()-1] == self.prev_search_str;
 
         nucleo_matcher.pattern.reparse(
             0, 
             ascii_input.as_str(), 
-            nucleo_matcher::pattern::CaseMatching::Ignore, 
-            nucleo_matcher::pattern::Normalization::Never, 
+            CaseMatching::Ignore, 
+            Normalization::Never, 
             is_string_extension);
         
         
         //println!("Tick {i}");
         // make matcher work, loop until it finishes, then retrieve snapshot of the result
-        while nucleo_matcher.tick(10).running {}
+        while nucleo_matcher.tick(10).running {
+            if cancel.is_some_and(|cancel| cancel.load(Ordering::Relaxed)) {
+                // the next search can't build on an unfinished one
+                self.prev_search_str.clear();
+                return false;
+            }
+        }
 
-        //println!("Nucleo status after tick {:?}", status);
-        //println!("result count {:?}", self.nucleo_matcher.snapshot().matched_item_count());
-        let max_display_result = std::cmp::min(10, nucleo_matcher.snapshot().matched_item_count());
-        let result = nucleo_matcher.snapshot().matched_items(0..max_display_result)
-            .into_iter()
-            .map(|item| item.data.clone() )
-            .collect::<Vec<EngineInputData>>();
+        self.prev_search_str = ascii_input;
+        return true;
+    }
+}
+
+/**
+ * Merges the results of the expansions of an abbreviated genus, each ranked for its own query.
+ * They are scored again against their query, and ranked like BatchMatcher does: best score first, then shortest string,
+ * ties in the order of the expansions, so homonyms keep their tie-break. An item found by several expansions keeps its best place.
+ */
+pub fn merge_expansions(mut results: Vec<(String, Vec<EngineInputData>)>, canonical: bool, limit: usize) -> Vec<EngineInputData> {
+    if results.len() == 1 {
+        return results.pop().map(|(_, matches)| matches).unwrap_or_default();
+    }
+
+    let mut matcher = Matcher::new(nucleo_matcher::Config::DEFAULT);
+    let mut ranked: Vec<(Reverse<u32>, usize, usize, EngineInputData)> = Vec::new();
+    for (query, matches) in results {
+        let pattern = Pattern::parse(&to_query(query, canonical), CaseMatching::Ignore, Normalization::Never);
+        for item in matches {
+            let haystack = to_haystack(&item, canonical);
+            let score = pattern.score(haystack.slice(..), &mut matcher).unwrap_or(0);
+            ranked.push((Reverse(score), haystack.len(), ranked.len(), item));
+        }
+    }
+    ranked.sort_by_key(|(score, length, position, _)| (*score, *length, *position));
+
+    let mut merged: Vec<EngineInputData> = Vec::with_capacity(limit);
+    for (_, _, _, item) in ranked {
+        if merged.len() == limit {
+            break;
+        }
+        if !merged.iter().any(|other| other.string == item.string && other.data == item.data) {
+            merged.push(item);
+        }
+    }
+    return merged;
+}
+
+impl Drop for EngineWrapper {
+    fn drop(&mut self) {
+        if let Some(owner) = &self.owner {
+            owner.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
 
-        self.prev_search_str = ascii_input.to_owned();
-        return result;
+// how an item is presented to the matcher
+pub fn to_haystack(input_data: &EngineInputData, canonical: bool) -> Utf32String {
+    if canonical {
+        return Utf32String::Ascii(names::canonical(&input_data.string).into());
     }
+    return Utf32String::Ascii(input_data.string.clone().into());
 }
 
+// how a query is presented to the matcher, the counterpart of to_haystack
+pub fn to_query(input: String, canonical: bool) -> String {
+    if canonical {
+        return to_ascii_query(names::canonical_query(&input));
+    }
+    return to_ascii_query(input);
+}
 
-pub async fn build_pool_ecosystem(input_data: &Vec<EngineInputData>, max_size: usize, min_size: usize) ->
-    (EnginePool, UsedEngineMap, Arc<Mutex<DelayQueue<Uuid, GrowingHeapBuf<Uuid>>>>, DelayQRx) {
+// what homonyms of the results are told apart with, nothing when matching whole strings
+pub fn to_query_authorship(input: &str, canonical: bool) -> Authorship {
+    if canonical {
+        return Authorship::of(&names::parse_query(input));
+    }
+    return Authorship::default();
+}
+
+// queries are matched as ascii only
+pub fn to_ascii_query(input: String) -> String {
+    if input.is_ascii() {
+        return input;
+    }
+    return deunicode::deunicode(input.as_str());
+}
+
+// like EnginePool::get(), but gives up after timeout seconds
+pub async fn get_engine(pool: &EnginePool, timeout: u64) -> Result<unmanaged::Object<EngineWrapper>, PoolError> {
+    return match tokio::time::timeout(std::time::Duration::from_secs(timeout), pool.get()).await {
+        Ok(result) => result,
+        Err(_) => Err(PoolError::Timeout),
+    };
+}
+
+// like EnginePool::remove(), but gives up after timeout seconds
+pub async fn remove_engine(pool: &EnginePool, timeout: u64) -> Result<EngineWrapper, PoolError> {
+    return match tokio::time::timeout(std::time::Duration::from_secs(timeout), pool.remove()).await {
+        Ok(result) => result,
+        Err(_) => Err(PoolError::Timeout),
+    };
+}
+
+pub async fn build_pool_ecosystem(input_data: &[EngineInputData], max_size: usize, min_size: usize, canonical: bool) ->
+    (EnginePool, EngineCount, UsedEngineMap, Arc<Mutex<DelayQueue<Uuid, GrowingHeapBuf<Uuid>>>>, DelayQRx) {
 
     let engine_pool = EnginePool::new(max_size);
+    let engine_count: EngineCount = Arc::new(AtomicUsize::new(0));
     for _i in 0..min_size {
-        let _ = engine_pool.add(EngineWrapper::new(input_data)).await;
+        let _ = engine_pool.add(EngineWrapper::new(input_data, canonical).owned_by(&engine_count)).await;
     }
-    println!("Pool {:?}", engine_pool.status());
+    tracing::debug!(status = ?engine_pool.status(), "Pool built");
 
     let (delay_queue , rx) = delay_queue::<Uuid>();
     let arcmut_used_engine: UsedEngineMap  = Arc::new(tok_Mutex::new(HashMap::new()));
 
     return (
         engine_pool,
+        engine_count,
         arcmut_used_engine,
         Arc::new(Mutex::new(delay_queue)),
         rx
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use super::{EngineCount, EngineWrapper};
+    use crate::io::test_items;
+
+    fn engine(canonical: bool) -> EngineWrapper {
+        return EngineWrapper::new(&test_items(&["Puma concolor (Linnaeus, 1771)", "Puma yagouaroundi", "Quercus robur L."]), canonical);
+    }
+
+    #[test]
+    fn owned_engines_counted_until_dropped() {
+        let count: EngineCount = Default::default();
+        let first = engine(false).owned_by(&count);
+        let second = engine(false).owned_by(&count);
+        assert_eq!(count.load(Ordering::Relaxed), 2);
+        drop(first);
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+        drop(second);
+        assert_eq!(count.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn blank_query_after_longer_one() {
+        // the blank query is empty once canonicalized, it used to underflow when compared to the previous one
+        let mut engine = engine(true);
+        assert_eq!(engine.fuzzy_match("Pu".to_owned(), 10).len(), 2);
+        engine.fuzzy_match("  ".to_owned(), 10);
+        assert_eq!(engine.fuzzy_match("Pum".to_owned(), 10).len(), 2);
+    }
+
+    #[test]
+    fn expansions_merged() {
+        let mut engine = EngineWrapper::new(&test_items(&["Eb coli bus", "Ea coli Smith, 1900", "Ea coli (Jones, 1850)"]), true);
+        let matches = engine.fuzzy_match_expanded(vec!["Eb coli".to_owned(), "Ea coli Jones".to_owned()], 2).into_iter()
+            .map(|item| item.string)
+            .collect::<Vec<String>>();
+        // same score, the shorter canonical name first, homonyms tie-broken by authorship
+        assert_eq!(matches, vec!["Ea coli (Jones, 1850)", "Ea coli Smith, 1900"]);
+    }
+
+    #[test]
+    fn extended_query_narrows() {
+        let mut engine = engine(false);
+        assert_eq!(engine.fuzzy_match("Pu".to_owned(), 10).len(), 2);
+        let matches = engine.fuzzy_match("Puma c".to_owned(), 10);
+        assert_eq!(matches[0].string, "Puma concolor (Linnaeus, 1771)");
+    }
+}
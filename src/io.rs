@@ -8,6 +8,15 @@ pub struct EngineInputData {
     pub data: serde_json::Value, // arbitrary data associated to it
 }
 
+// what was loaded, for reporting
+#[derive(Debug, Clone)]
+pub struct DatasetInfo {
+    pub path: String,
+    pub size: usize,
+    // incremented each time the dataset is replaced, it is only loaded once at startup for now
+    pub generation: u64,
+}
+
 pub fn from_file(filename: String) -> Vec<EngineInputData> {
     let bufread = BufReader::new(File::open(filename).unwrap());
 
@@ -18,12 +27,20 @@ pub fn from_file(filename: String) -> Vec<EngineInputData> {
     return input_data;
 }
 
-pub fn to_hashmap(input_data_vec: &Vec<EngineInputData>) -> HashMap<String, EngineInputData> {
+pub fn to_hashmap(input_data_vec: &[EngineInputData]) -> HashMap<String, EngineInputData> {
     let mut result: HashMap<String, EngineInputData> = HashMap::new();
 
-    for input_data in input_data_vec.to_owned() {
+    for input_data in input_data_vec.iter().cloned() {
         result.insert(input_data.string.clone(), input_data);
     }
 
     return result;
-}
\ No newline at end of file
+}
+
+// items with the given strings and no data, for tests
+#[cfg(test)]
+pub fn test_items(strings: &[&str]) -> Vec<EngineInputData> {
+    return strings.iter()
+        .map(|string| EngineInputData { string: string.to_string(), data: serde_json::Value::Null })
+        .collect();
+}
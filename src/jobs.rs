@@ -0,0 +1,276 @@
+use std::{collections::HashMap, fs::{self, File, OpenOptions}, io::{self, BufRead, BufReader, Write}, path::PathBuf, sync::Arc, time::{SystemTime, UNIX_EPOCH}};
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{io::EngineInputData, routes::fuzzy_match::{match_chunk, StringMatches}, AppState};
+
+// strings matched between two progress updates
+const JOB_CHUNK_SIZE: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Done,
+    Failed,
+}
+
+/**
+ * What is known of a job, persisted as job.json in the job directory, next to input.json (the strings)
+ * and results.ndjson (the StringMatches of each string, one line per string, in order).
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobInfo {
+    pub id: String,
+    pub status: JobStatus,
+    // name of the API key that created the job, only that key can see it
+    pub owner: Option<String>,
+    pub n_first_results: usize,
+    pub total: usize,
+    pub done: usize,
+    // unix timestamps, in seconds
+    pub created_at: u64,
+    pub finished_at: Option<u64>,
+    pub error: Option<String>,
+}
+
+// a line of results.ndjson, jobs started before abbreviations were recorded have their matches only
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredResult {
+    Matches(StringMatches),
+    MatchesOnly(Vec<EngineInputData>),
+}
+
+/**
+ * Jobs on disk, one directory each under the jobs directory, and their infos in memory.
+ * Finished jobs are deleted retention seconds after they finish.
+ */
+pub struct JobStore {
+    dir: PathBuf,
+    retention: u64,
+    jobs: Mutex<HashMap<String, JobInfo>>,
+}
+
+impl JobStore {
+    /**
+     * Opens the jobs directory, creating it if needed, and loads every job found in it.
+     */
+    pub fn open(dir: &str, retention: u64) -> Result<Self, String> {
+        fs::create_dir_all(dir).map_err(|e| format!("Cannot create jobs directory {}: {}", dir, e))?;
+
+        let mut jobs = HashMap::new();
+        for entry in fs::read_dir(dir).map_err(|e| format!("Cannot read jobs directory {}: {}", dir, e))? {
+            let info_path = entry.map_err(|e| e.to_string())?.path().join("job.json");
+            let info = fs::read(&info_path).ok()
+                .and_then(|content| serde_json::from_slice::<JobInfo>(&content).ok());
+            match info {
+                Some(info) => { jobs.insert(info.id.clone(), info); },
+                None => tracing::warn!(path = ?info_path, "Unreadable job, ignored"),
+            }
+        }
+
+        return Ok(JobStore { dir: PathBuf::from(dir), retention, jobs: Mutex::new(jobs) });
+    }
+
+    pub fn create(&self, strings: &[String], n_first_results: usize, owner: Option<String>) -> io::Result<JobInfo> {
+        let info = JobInfo {
+            id: Uuid::new_v4().to_string(),
+            status: JobStatus::Queued,
+            owner,
+            n_first_results,
+            total: strings.len(),
+            done: 0,
+            created_at: now(),
+            finished_at: None,
+            error: None,
+        };
+
+        fs::create_dir_all(self.job_dir(&info.id))?;
+        fs::write(self.input_path(&info.id), serde_json::to_vec(strings)?)?;
+        File::create(self.results_path(&info.id))?;
+        self.save(&info)?;
+        return Ok(info);
+    }
+
+    pub fn get(&self, id: &str) -> Option<JobInfo> {
+        return self.jobs.lock().get(id).cloned();
+    }
+
+    // jobs that were queued or running when the server stopped
+    pub fn unfinished(&self) -> Vec<String> {
+        return self.jobs.lock().values()
+            .filter(|info| info.status == JobStatus::Queued || info.status == JobStatus::Running)
+            .map(|info| info.id.clone())
+            .collect();
+    }
+
+    // written to a temporary file first, so a crash never leaves a truncated job.json
+    fn save(&self, info: &JobInfo) -> io::Result<()> {
+        let tmp_path = self.job_dir(&info.id).join("job.json.tmp");
+        fs::write(&tmp_path, serde_json::to_vec(info)?)?;
+        fs::rename(&tmp_path, self.job_dir(&info.id).join("job.json"))?;
+        self.jobs.lock().insert(info.id.clone(), info.clone());
+        return Ok(());
+    }
+
+    pub fn read_input(&self, id: &str) -> io::Result<Vec<String>> {
+        return Ok(serde_json::from_slice(&fs::read(self.input_path(id))?)?);
+    }
+
+    pub fn read_results(&self, id: &str) -> io::Result<Vec<StringMatches>> {
+        let reader = BufReader::new(File::open(self.results_path(id))?);
+        let mut results = Vec::new();
+        for line in reader.lines() {
+            results.push(match serde_json::from_str(&line?)? {
+                StoredResult::Matches(string_matches) => string_matches,
+                StoredResult::MatchesOnly(matches) => StringMatches { matches, abbreviation: None },
+            });
+        }
+        return Ok(results);
+    }
+
+    /**
+     * Number of strings whose results are on disk. A line half written when the server stopped is removed.
+     */
+    fn resume_point(&self, id: &str) -> io::Result<usize> {
+        let path = self.results_path(id);
+        let content = fs::read(&path)?;
+        let complete = content.iter().rposition(|byte| *byte == b'\n').map(|position| position + 1).unwrap_or(0);
+        if complete < content.len() {
+            OpenOptions::new().write(true).open(&path)?.set_len(complete as u64)?;
+        }
+        return Ok(content[..complete].iter().filter(|byte| **byte == b'\n').count());
+    }
+
+    fn append_results(&self, id: &str, results: &[StringMatches]) -> io::Result<()> {
+        let mut encoded = Vec::new();
+        for result in results {
+            serde_json::to_writer(&mut encoded, result)?;
+            encoded.push(b'\n');
+        }
+        let mut file = OpenOptions::new().append(true).open(self.results_path(id))?;
+        file.write_all(&encoded)?;
+        return file.sync_data();
+    }
+
+    /**
+     * Deletes the jobs that finished more than retention seconds ago.
+     */
+    pub fn remove_expired(&self) {
+        let expiry = now().saturating_sub(self.retention);
+        let expired = self.jobs.lock().values()
+            .filter(|info| info.finished_at.is_some_and(|finished_at| finished_at < expiry))
+            .map(|info| info.id.clone())
+            .collect::<Vec<String>>();
+
+        for id in expired {
+            if let Err(e) = fs::remove_dir_all(self.job_dir(&id)) {
+                tracing::warn!(job_id = id, error = %e, "Cannot delete expired job");
+                continue;
+            }
+            self.jobs.lock().remove(&id);
+            tracing::info!(job_id = id, "Expired job deleted");
+        }
+    }
+
+    fn job_dir(&self, id: &str) -> PathBuf {
+        return self.dir.join(id);
+    }
+
+    fn input_path(&self, id: &str) -> PathBuf {
+        return self.job_dir(id).join("input.json");
+    }
+
+    fn results_path(&self, id: &str) -> PathBuf {
+        return self.job_dir(id).join("results.ndjson");
+    }
+}
+
+fn now() -> u64 {
+    return SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or(0);
+}
+
+// runs f on the store in a blocking thread, job files are read and written synchronously, and synced
+async fn with_store<T, F>(store: &Arc<JobStore>, f: F) -> io::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&JobStore) -> io::Result<T> + Send + 'static,
+{
+    let store = store.clone();
+    return tokio::task::spawn_blocking(move || f(&store)).await.map_err(io::Error::other)?;
+}
+
+/**
+ * Deletes expired jobs (see JobStore::remove_expired) in a blocking thread.
+ */
+pub async fn remove_expired_jobs(store: &Arc<JobStore>) {
+    let _ = with_store(store, |store| {
+        store.remove_expired();
+        return Ok(());
+    }).await;
+}
+
+/**
+ * Runs a job to the end, from where it stopped if it was already started.
+ * Strings are matched chunk by chunk with the batch workers, like /fuzzy_match, results being saved after each chunk.
+ */
+pub async fn run_job(appstate: AppState, id: String) {
+    if let Err(e) = run_job_chunks(&appstate, &id).await {
+        tracing::error!(job_id = id, error = %e, "Job failed");
+        if let Some(mut info) = appstate.job_store.get(&id) {
+            info.status = JobStatus::Failed;
+            info.error = Some(e.to_string());
+            info.finished_at = Some(now());
+            let _ = save_info(&appstate.job_store, &info).await;
+        }
+    }
+}
+
+async fn run_job_chunks(appstate: &AppState, id: &str) -> io::Result<()> {
+    let store = &appstate.job_store;
+    let Some(mut info) = store.get(id) else {
+        return Ok(());
+    };
+    let job_id = id.to_owned();
+    let (strings, done) = with_store(store, move |store| Ok((store.read_input(&job_id)?, store.resume_point(&job_id)?))).await?;
+    info.done = done;
+    info.status = JobStatus::Running;
+    save_info(store, &info).await?;
+    tracing::info!(job_id = id, done = info.done, total = info.total, "Job running");
+
+    while info.done < strings.len() {
+        let chunk = strings[info.done..std::cmp::min(info.done + JOB_CHUNK_SIZE, strings.len())].to_vec();
+        let chunk_len = chunk.len();
+
+        let worker = appstate.batch_workers.clone().acquire_owned().await
+            .map_err(io::Error::other)?;
+        let batch_matcher = appstate.batch_matcher.clone();
+        let limit = info.n_first_results;
+        let (results, zero_results) = tokio::task::spawn_blocking(move || {
+            let result = match_chunk(&batch_matcher, chunk, limit);
+            drop(worker);
+            return result;
+        }).await.map_err(io::Error::other)?;
+        appstate.metrics.zero_result_queries.with_label_values(&["/jobs"]).inc_by(zero_results);
+
+        let job_id = id.to_owned();
+        with_store(store, move |store| store.append_results(&job_id, &results)).await?;
+        info.done += chunk_len;
+        save_info(store, &info).await?;
+    }
+
+    info.status = JobStatus::Done;
+    info.finished_at = Some(now());
+    save_info(store, &info).await?;
+    tracing::info!(job_id = id, total = info.total, "Job done");
+    return Ok(());
+}
+
+async fn save_info(store: &Arc<JobStore>, info: &JobInfo) -> io::Result<()> {
+    let info = info.clone();
+    return with_store(store, move |store| store.save(&info)).await;
+}
@@ -0,0 +1,47 @@
+use axum::{extract::MatchedPath, http::Request};
+use tracing::Span;
+use tracing_subscriber::EnvFilter;
+
+use crate::config::ServerConfig;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/**
+ * Installs the global subscriber. RUST_LOG, when set, takes precedence over the configured log_level.
+ */
+pub fn init(server_config: &ServerConfig) {
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new(&server_config.log_level));
+
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    if server_config.log_format == "json" {
+        builder.json().with_current_span(true).with_span_list(false).init();
+    } else {
+        builder.init();
+    }
+}
+
+/**
+ * Span wrapping each request. session_id and engine_uuid are recorded by the handlers that know them.
+ * The request id is set by SetRequestIdLayer, which must run before this.
+ */
+pub fn make_request_span<B>(request: &Request<B>) -> Span {
+    let request_id = request.headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("");
+    let route = request.extensions()
+        .get::<MatchedPath>()
+        .map(|matched_path| matched_path.as_str())
+        .unwrap_or("unmatched");
+
+    return tracing::info_span!(
+        "request",
+        request_id,
+        method = %request.method(),
+        route,
+        api_key = tracing::field::Empty,
+        session_id = tracing::field::Empty,
+        engine_uuid = tracing::field::Empty,
+    );
+}
@@ -1,18 +1,44 @@
-use std::{fs::File, sync::{Arc, Mutex}, collections::HashMap};
+#![allow(clippy::needless_return)]
 
-use axum::{Router, routing::post};
+use std::{sync::Arc, collections::HashMap, net::SocketAddr, str::FromStr, time::Instant};
+
+use axum::{extract::DefaultBodyLimit, http::{HeaderName, HeaderValue, Method}, middleware, Router, routing::{get, post}};
 //use axum_macros::debug_handler;
 use clap::Parser;
+use config::{Args, ServerConfig};
 use futures_delay_queue::DelayQueue;
 use futures_intrusive::buffer::GrowingHeapBuf;
-use io::EngineInputData;
+use parking_lot::Mutex;
+use auth::ApiKeys;
+use batch::BatchMatcher;
+use cache::{PrefixCache, SharedPrefixCache};
+use io::{DatasetInfo, EngineInputData};
+use jobs::JobStore;
+use metrics::Metrics;
+use normalize::Normalizer;
+use rate_limit::{ClientEngines, ClientLimiter};
 use time::Duration;
-use tower_sessions::{MemoryStore, SessionManagerLayer, Expiry};
+use tower_http::{cors::{AllowOrigin, CorsLayer}, request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer}, trace::{DefaultOnRequest, DefaultOnResponse, TraceLayer}, LatencyUnit};
+use tracing::Level;
+use tower_sessions::{cookie::SameSite, MemoryStore, SessionManagerLayer, Expiry};
 use uuid::Uuid;
 
+mod auth;
+mod batch;
+mod bench;
+mod cache;
+mod config;
+mod csv_table;
 mod engine;
 mod io;
+mod jobs;
+mod logging;
+mod metrics;
+mod names;
+mod normalize;
+mod rate_limit;
 mod routes;
+mod tls;
 
 
 //use crate::engine::EngineWrapper;
@@ -20,91 +46,76 @@ mod routes;
 use crate::engine::*;
 
 const SESSION_ENGINE_KEY: &str = "engine";
+const SESSION_FIELDS_KEY: &str = "engine_fields";
 
 
-#[derive(Parser, Debug)]
-#[command(version)]
-struct Args {
-    #[arg(short= 'i', long = "input", value_parser = valid_file)]
-    json_input: String,
-}
-
-fn valid_file(s: &str) -> Result<String, String> {
-    // simply check if string is an openable file
-    match File::open(s) {
-        Ok(_) => Ok(s.to_owned()),
-        Err(e) => Err(e.to_string()),
-    }
-}
-
 #[derive(Clone)]
 struct AppState {
     server_config: ServerConfig,
+    started_at: Instant,
+    dataset: Arc<DatasetInfo>,
     db_hashmap: Arc<HashMap<String, EngineInputData>>,
+    // same items, keyed by their normalized string
+    db_normalized: Arc<HashMap<String, EngineInputData>>,
+    normalizer: Arc<Normalizer>,
 
     // dedicated to autocomplete
     autocomplete_engine_pool: EnginePool,
+    // engines of the pool, including the ones taken out of it
+    autocomplete_engine_count: EngineCount,
     autocomplete_used_engines: UsedEngineMap, // this thing could become the bottleneck
     autocomplete_delay_q: Arc<Mutex<DelayQueue<Uuid, GrowingHeapBuf<Uuid>>>>,
 
+    // one-shot batch matching, shared by all requests, a worker permit is needed to use it
+    batch_matcher: Arc<BatchMatcher>,
+    batch_workers: Arc<tokio::sync::Semaphore>,
+
     // general purpose
     gp_engine_pool: EnginePool,
+    gp_engine_count: EngineCount,
+    #[allow(dead_code)] // no gp route holds an engine across requests yet
     gp_used_engines: UsedEngineMap,
+    #[allow(dead_code)]
     gp_delay_q: Arc<Mutex<DelayQueue<Uuid, GrowingHeapBuf<Uuid>>>>,
-}
 
-#[derive(Debug, Clone)]
-struct ServerConfig {
-    // engine pool specificly for autocomplete
-    autocomplete_pool_max_size: usize,
-    autocomplete_pool_min_size: usize,
-    session_expiry_delay: u64, // in seconds
-    /*
-        We absolutely want to avoid valid sessions trying to use expired engines that have been returned back to the pool.
-        So the delay for returning en engine should be more than the expiry of a session.
-        On the other hand it is ok if a usable engine sits unused because its session has expired.
-        Total expiry of an engine will be session_expiry_delay + engine_returned_additional_delay.
-     */
-    engine_returned_additional_delay: u64, // in seconds
-
-    // general purpose engine pool for other functions
-    gp_pool_max_size: usize,
-    gp_pool_min_size: usize,
-}
-
-impl Default for ServerConfig {
-    fn default() -> Self {
-        Self { 
-            autocomplete_pool_max_size: 10,
-            autocomplete_pool_min_size: 2,
-            session_expiry_delay: 10,
-            engine_returned_additional_delay: 2,
-            gp_pool_max_size: 10,
-            gp_pool_min_size: 2,
-        }
-    }
-}
-
-impl ServerConfig {
-    fn get_engine_expiry(&self) -> u64 {
-        return self.session_expiry_delay + self.engine_returned_additional_delay;
-    }
+    job_store: Arc<JobStore>,
+    metrics: Arc<Metrics>,
+    // None when no keys file is configured, authentication is then off
+    api_keys: Option<Arc<ApiKeys>>,
+    // None when rate limiting is off
+    client_limiter: Option<Arc<ClientLimiter>>,
+    client_engines: Arc<ClientEngines>,
+    session_store: MemoryStore,
+    prefix_cache: SharedPrefixCache,
 }
 
 async fn engine_cleanup_handler(
     rx: DelayQRx,
     arcmutex_used_engine: UsedEngineMap,
     engine_pool: EnginePool,
+    metrics: Arc<Metrics>,
+    client_engines: Arc<ClientEngines>,
+    pool_name: &'static str,
 ) {
     //let mut interval = tokio::time::interval(std::time::Duration::from_secs(5));
     loop {
-        println!("awaiting timers");
+        tracing::debug!(pool = pool_name, "awaiting timers");
         match rx.receive().await {
             Some(uuid_to_remove) => {
-                println!("Putting back engine id {:?}", uuid_to_remove);
+                tracing::debug!(pool = pool_name, engine_uuid = %uuid_to_remove, "Putting back engine");
                 let mut lock = arcmutex_used_engine.lock().await;
-                let (engine, _) = lock.remove(&uuid_to_remove).unwrap();
-                let _ = engine_pool.add(engine).await;
+                match lock.remove(&uuid_to_remove) {
+                    Some((engine, _)) => {
+                        let _ = engine_pool.add(engine).await;
+                        client_engines.release(&uuid_to_remove);
+                        metrics.engines_reclaimed.with_label_values(&[pool_name]).inc();
+                    },
+                    None => {
+                        // the engine was already taken out by a request handler, which is now responsible for it
+                        tracing::warn!(pool = pool_name, engine_uuid = %uuid_to_remove, "No engine found, nothing to put back");
+                        metrics.cleanup_missing_engines.inc();
+                    },
+                }
             },
             None => {
                 // the channel was closed
@@ -114,53 +125,177 @@ async fn engine_cleanup_handler(
     }
 }
 
+/**
+ * CORS as configured. Without allowed origins, no CORS header is ever sent, so browsers keep blocking cross-origin calls.
+ */
+fn build_cors_layer(server_config: &ServerConfig) -> CorsLayer {
+    if server_config.cors_allowed_origins.is_empty() {
+        return CorsLayer::new();
+    }
+
+    // values were checked when loading the config
+    let allow_origin = if server_config.cors_allowed_origins.iter().any(|origin| origin == "*") {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(server_config.cors_allowed_origins.iter().map(|origin| HeaderValue::from_str(origin).unwrap()))
+    };
+    let methods = server_config.cors_allowed_methods.iter()
+        .map(|method| Method::from_str(method).unwrap())
+        .collect::<Vec<Method>>();
+    let headers = server_config.cors_allowed_headers.iter()
+        .map(|header| HeaderName::from_str(header).unwrap())
+        .collect::<Vec<HeaderName>>();
+
+    return CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods(methods)
+        .allow_headers(headers)
+        .allow_credentials(server_config.cors_allow_credentials)
+        .expose_headers([HeaderName::from_static(logging::REQUEST_ID_HEADER)]);
+}
+
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
-    println!("Json file location: {:?}", args.json_input);
+    let bench_queries = args.bench.clone();
+    let server_config = match ServerConfig::load(args) {
+        Ok(server_config) => server_config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            std::process::exit(1);
+        },
+    };
+    logging::init(&server_config);
+    tracing::info!(config = ?server_config, "Config loaded");
+    tracing::info!("Json file location: {:?}", server_config.dataset_path);
 
-    let json_input = io::from_file(args.json_input);
+    let json_input = io::from_file(server_config.dataset_path.clone());
     let json_input_ashashmap = io::to_hashmap(&json_input);
-    
-    let server_config = ServerConfig::default(); 
+    let canonical = server_config.is_match_on_canonical();
+    if let Some(bench_queries) = bench_queries {
+        if let Err(e) = bench::run(&json_input, &bench_queries, server_config.fuzzy_match_result_limit, canonical, server_config.max_abbreviation_expansions) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let dataset = DatasetInfo { path: server_config.dataset_path.clone(), size: json_input.len(), generation: 1 };
 
     // build autocomplete engine pool
     let (autocomplete_engine_pool, 
+        autocomplete_engine_count, 
         arcmut_autocmplt_used_engine, 
         autocomplete_delay_queue, 
         autocomplete_rx) = build_pool_ecosystem(
         &json_input, 
         server_config.autocomplete_pool_max_size, 
-        server_config.autocomplete_pool_min_size).await;
+        server_config.autocomplete_pool_min_size,
+        canonical).await;
     
     // build general purpose engine pool
     let (gp_engine_pool, 
+        gp_engine_count, 
         arcmut_gp_used_engine, 
         gp_delay_queue, 
         gp_rx) = build_pool_ecosystem(
         &json_input, 
         server_config.gp_pool_max_size, 
-        server_config.gp_pool_min_size).await;
+        server_config.gp_pool_min_size,
+        canonical).await;
 
 
+    let api_keys = match &server_config.api_keys_path {
+        Some(api_keys_path) => match ApiKeys::from_file(api_keys_path) {
+            Ok(api_keys) => {
+                tracing::info!("{} API keys loaded from {}", api_keys.len(), api_keys_path);
+                Some(Arc::new(api_keys))
+            },
+            Err(e) => {
+                tracing::error!("{}", e);
+                std::process::exit(1);
+            },
+        },
+        None => None,
+    };
+
+    let job_store = match JobStore::open(&server_config.jobs_dir, server_config.job_retention) {
+        Ok(job_store) => Arc::new(job_store),
+        Err(e) => {
+            tracing::error!("{}", e);
+            std::process::exit(1);
+        },
+    };
+
+    let normalizer = Normalizer::new(&server_config.normalization);
+    let metrics = Arc::new(Metrics::new());
+    let client_limiter = match server_config.client_requests_per_minute {
+        0 => None,
+        requests_per_minute => Some(Arc::new(ClientLimiter::new(requests_per_minute, server_config.client_burst))),
+    };
+    let client_engines = Arc::new(ClientEngines::new(server_config.max_engines_per_client));
+
+    // shared between the session layer (cookie sessions) and the autocomplete route (token sessions)
+    let session_store = MemoryStore::default();
+
     let appstate = AppState {
         server_config: server_config.clone(),
+        started_at: Instant::now(),
+        dataset: Arc::new(dataset),
         db_hashmap: Arc::new(json_input_ashashmap),
+        db_normalized: Arc::new(normalize::to_normalized_hashmap(&json_input, &normalizer)),
+        normalizer: Arc::new(normalizer),
         autocomplete_engine_pool: autocomplete_engine_pool.clone(), 
+        autocomplete_engine_count,
         autocomplete_used_engines: arcmut_autocmplt_used_engine.clone(),
         autocomplete_delay_q: autocomplete_delay_queue,
+        batch_matcher: Arc::new(BatchMatcher::new(&json_input, canonical, server_config.max_abbreviation_expansions)),
+        batch_workers: Arc::new(tokio::sync::Semaphore::new(server_config.batch_workers)),
         gp_engine_pool: gp_engine_pool.clone(),
+        gp_engine_count,
         gp_used_engines: arcmut_gp_used_engine.clone(),
         gp_delay_q: gp_delay_queue,
+        job_store: job_store.clone(),
+        metrics: metrics.clone(),
+        api_keys,
+        client_limiter: client_limiter.clone(),
+        client_engines: client_engines.clone(),
+        session_store: session_store.clone(),
+        prefix_cache: Arc::new(Mutex::new(PrefixCache::new(server_config.prefix_cache_capacity))),
     };
 
-    let session_store = MemoryStore::default();
     let session_layer = SessionManagerLayer::new(session_store)
-        .with_secure(false) // TODO why is session not working without this, and only when false ?
+        // browsers don't send secure cookies back over plain http, so they can only be secure with TLS
+        .with_secure(server_config.is_tls_enabled())
+        .with_same_site(match server_config.cookie_same_site.as_str() {
+            "none" => SameSite::None,
+            "lax" => SameSite::Lax,
+            _ => SameSite::Strict,
+        })
         .with_expiry(Expiry::OnInactivity(Duration::seconds(server_config.session_expiry_delay as i64)));
 
-    let _ = tokio::spawn(engine_cleanup_handler(autocomplete_rx, arcmut_autocmplt_used_engine, autocomplete_engine_pool));
-    let _ = tokio::spawn(engine_cleanup_handler(gp_rx, arcmut_gp_used_engine, gp_engine_pool));
+    tokio::spawn(engine_cleanup_handler(autocomplete_rx, arcmut_autocmplt_used_engine, autocomplete_engine_pool, metrics.clone(), client_engines.clone(), "autocomplete"));
+    tokio::spawn(engine_cleanup_handler(gp_rx, arcmut_gp_used_engine, gp_engine_pool, metrics, client_engines, "gp"));
+    for job_id in job_store.unfinished() {
+        tracing::info!(job_id, "Resuming job");
+        tokio::spawn(jobs::run_job(appstate.clone(), job_id));
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            jobs::remove_expired_jobs(&job_store).await;
+        }
+    });
+    if let Some(client_limiter) = client_limiter {
+        // forget clients that stopped sending requests
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                client_limiter.prune();
+            }
+        });
+    }
     //let _ = forever.await;
     
 
@@ -171,10 +306,51 @@ async fn main() {
         .route("/fuzzy", post(routes::fuzzy_autocomplete::fuzzy_autocomplete))
         .route("/exact_match", post(routes::exact_match::exact_match))
         .route("/fuzzy_match", post(routes::fuzzy_match::fuzzy_match))
+        .route("/fuzzy_match/stream", post(routes::fuzzy_match_stream::fuzzy_match_stream))
+        .route("/match", post(routes::match_cascade::match_cascade))
+        .route("/reconcile/csv", post(routes::reconcile::reconcile_csv))
+        .route("/parse", post(routes::parse::parse))
+        .route("/jobs", post(routes::jobs::create_job))
+        .route("/jobs/:id", get(routes::jobs::get_job))
+        .route("/jobs/:id/results", get(routes::jobs::job_results))
+        .route("/ws/autocomplete", get(routes::ws_autocomplete::ws_autocomplete))
+        .route("/healthz", get(routes::status::healthz))
+        .route("/readyz", get(routes::status::readyz))
+        .route("/status", get(routes::status::status))
+        .route("/metrics", get(routes::metrics::metrics))
+        .layer(DefaultBodyLimit::max(server_config.max_body_size))
+        .route_layer(middleware::from_fn_with_state(appstate.clone(), rate_limit::limit_clients))
+        .route_layer(middleware::from_fn_with_state(appstate.clone(), auth::authenticate))
+        // outside authentication, so that rejected requests are counted too
+        .route_layer(middleware::from_fn_with_state(appstate.clone(), routes::metrics::track_requests))
+        // inside the session layer, so that the request span is the current one in handlers
+        .layer(TraceLayer::new_for_http()
+            .make_span_with(logging::make_request_span)
+            .on_request(DefaultOnRequest::new().level(Level::DEBUG))
+            .on_response(DefaultOnResponse::new().level(Level::INFO).latency_unit(LatencyUnit::Micros)))
         .layer(session_layer)
+        // outside the session layer, preflight requests are answered without touching the session
+        .layer(build_cors_layer(&server_config))
+        .layer(PropagateRequestIdLayer::new(HeaderName::from_static(logging::REQUEST_ID_HEADER)))
+        .layer(SetRequestIdLayer::new(HeaderName::from_static(logging::REQUEST_ID_HEADER), MakeRequestUuid))
         .with_state(appstate);
 
-    // run our app with hyper, listening globally on port 3000
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    // run our app with hyper, listening by default globally on port 3000
+    if server_config.is_tls_enabled() {
+        let tls_config = match tls::load_rustls_config(&server_config).await {
+            Ok(tls_config) => tls_config,
+            Err(e) => {
+                tracing::error!("TLS setup failed: {}", e);
+                std::process::exit(1);
+            },
+        };
+        let listener = std::net::TcpListener::bind(server_config.get_bind_address()).unwrap();
+        listener.set_nonblocking(true).unwrap();
+        tracing::info!("Listening on https://{}", server_config.get_bind_address());
+        axum_server::from_tcp_rustls(listener, tls_config).serve(app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
+    } else {
+        let listener = tokio::net::TcpListener::bind(server_config.get_bind_address()).await.unwrap();
+        tracing::info!("Listening on http://{}", server_config.get_bind_address());
+        axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
+    }
 }
\ No newline at end of file
@@ -0,0 +1,112 @@
+use prometheus::{Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, IntGauge, Opts, Registry, TextEncoder};
+
+/**
+ * Every metric of the server, registered in its own registry and exposed on /metrics.
+ * Pool and session gauges are not updated by the handlers, they are read from the pools when scraped.
+ */
+pub struct Metrics {
+    registry: Registry,
+
+    // per route, labelled with the matched path
+    pub requests: IntCounterVec,
+    pub request_duration: HistogramVec,
+    pub zero_result_queries: IntCounterVec,
+
+    // labelled with the pool name
+    pub pool_size: IntGaugeVec,
+    pub pool_available: IntGaugeVec,
+    pub pool_in_use: IntGaugeVec,
+    // engines put back in their pool by engine_cleanup_handler
+    pub engines_reclaimed: IntCounterVec,
+
+    pub autocomplete_sessions: IntGauge,
+    // a session pointed to an engine that was not in the used engine map anymore, a new one had to be acquired
+    pub autocomplete_stale_engines: IntCounter,
+    // the delay queue fired for an engine that was not in the used engine map
+    pub cleanup_missing_engines: IntCounter,
+    // stateless autocomplete queries answered from a cached prefix, or not
+    pub prefix_cache_hits: IntCounter,
+    pub prefix_cache_misses: IntCounter,
+
+    // requests accepted per API key and route, and rejected ones per key and error code (empty key when unknown)
+    pub api_key_requests: IntCounterVec,
+    pub api_key_rejections: IntCounterVec,
+    // requests refused by the per client limits, by error code
+    pub client_rejections: IntCounterVec,
+
+    // time spent matching in /fuzzy_match, whole batch
+    pub fuzzy_match_duration: Histogram,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("fuzzy_taxo_search".to_owned()), None).unwrap();
+
+        let metrics = Metrics {
+            requests: IntCounterVec::new(
+                Opts::new("http_requests_total", "Requests received, by route and status"),
+                &["route", "status"]).unwrap(),
+            request_duration: HistogramVec::new(
+                HistogramOpts::new("http_request_duration_seconds", "Request latency, by route"),
+                &["route"]).unwrap(),
+            zero_result_queries: IntCounterVec::new(
+                Opts::new("zero_result_queries_total", "Queries that matched nothing, by route"),
+                &["route"]).unwrap(),
+            pool_size: IntGaugeVec::new(Opts::new("engine_pool_size", "Engines owned by the pool, including the ones taken out of it"), &["pool"]).unwrap(),
+            pool_available: IntGaugeVec::new(Opts::new("engine_pool_available", "Engines available in the pool"), &["pool"]).unwrap(),
+            pool_in_use: IntGaugeVec::new(
+                Opts::new("engine_pool_in_use", "Engines in use, including the ones attributed to sessions"),
+                &["pool"]).unwrap(),
+            engines_reclaimed: IntCounterVec::new(
+                Opts::new("engines_reclaimed_total", "Engines put back in their pool after expiry"),
+                &["pool"]).unwrap(),
+            autocomplete_sessions: IntGauge::new("autocomplete_active_sessions", "Autocomplete sessions holding an engine").unwrap(),
+            autocomplete_stale_engines: IntCounter::new(
+                "autocomplete_stale_engines_total",
+                "Sessions whose engine was gone, and got a new one").unwrap(),
+            cleanup_missing_engines: IntCounter::new(
+                "cleanup_missing_engines_total",
+                "Expired engines that were not found when trying to put them back").unwrap(),
+            prefix_cache_hits: IntCounter::new("prefix_cache_hits_total", "Stateless autocomplete queries served from the prefix cache").unwrap(),
+            prefix_cache_misses: IntCounter::new("prefix_cache_misses_total", "Stateless autocomplete queries served by an engine").unwrap(),
+            api_key_requests: IntCounterVec::new(
+                Opts::new("api_key_requests_total", "Requests accepted, by API key name and route"),
+                &["key", "route"]).unwrap(),
+            api_key_rejections: IntCounterVec::new(
+                Opts::new("api_key_rejections_total", "Requests rejected by authentication, by API key name and error code"),
+                &["key", "code"]).unwrap(),
+            client_rejections: IntCounterVec::new(
+                Opts::new("client_rejections_total", "Requests refused by the per client limits, by error code"),
+                &["code"]).unwrap(),
+            fuzzy_match_duration: Histogram::with_opts(
+                HistogramOpts::new("fuzzy_match_matching_seconds", "Time spent matching a /fuzzy_match batch")).unwrap(),
+            registry,
+        };
+
+        metrics.registry.register(Box::new(metrics.requests.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.request_duration.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.zero_result_queries.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.pool_size.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.pool_available.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.pool_in_use.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.engines_reclaimed.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.autocomplete_sessions.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.autocomplete_stale_engines.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.cleanup_missing_engines.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.prefix_cache_hits.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.prefix_cache_misses.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.api_key_requests.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.api_key_rejections.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.client_rejections.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.fuzzy_match_duration.clone())).unwrap();
+
+        return metrics;
+    }
+
+    // Prometheus text format
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer).unwrap();
+        return String::from_utf8(buffer).unwrap();
+    }
+}
@@ -0,0 +1,506 @@
+use serde::Serialize;
+
+use crate::io::EngineInputData;
+
+/**
+ * A scientific name split into its parts, see parse.
+ */
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ParsedName {
+    // genus, or a name above the genus
+    pub uninomial: Option<String>,
+    pub infrageneric_epithet: Option<Epithet>,
+    pub specific_epithet: Option<String>,
+    pub infraspecific_epithets: Vec<Epithet>,
+    // a hybrid sign was found, before the genus or an epithet
+    pub hybrid: bool,
+    // of the last epithet, as written, year included
+    pub authorship: Option<String>,
+    pub year: Option<u16>,
+    // the epithets alone, without rank markers, hybrid signs nor authorship, e.g. "Puma concolor"
+    pub canonical: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Epithet {
+    pub epithet: String,
+    // normalized rank marker, "subsp." for "ssp.", none for zoological trinomials or "Quercus (Lepidobalanus)"
+    pub rank: Option<String>,
+}
+
+// as written without the final dot, normalized form, and whether the rank is between the genus and the species
+const RANK_MARKERS: [(&str, &str, bool); 16] = [
+    ("subg", "subg.", true),
+    ("subgen", "subg.", true),
+    ("sect", "sect.", true),
+    ("subsect", "subsect.", true),
+    ("ser", "ser.", true),
+    ("subsp", "subsp.", false),
+    ("ssp", "subsp.", false),
+    ("nothosubsp", "nothosubsp.", false),
+    ("var", "var.", false),
+    ("nothovar", "nothovar.", false),
+    ("subvar", "subvar.", false),
+    ("f", "f.", false),
+    ("fo", "f.", false),
+    ("forma", "f.", false),
+    ("subf", "subf.", false),
+    ("morph", "morph.", false),
+];
+
+const HYBRID_SIGN: char = '×';
+
+// lowercase words starting an author's name, "van der Wulp", "de Candolle", "d' Orbigny"
+const AUTHOR_PARTICLES: [&str; 7] = ["de", "van", "der", "von", "d'", "la", "le"];
+
+// words of an authorship that don't name anyone
+const AUTHORSHIP_CONNECTORS: [&str; 7] = ["ex", "in", "et", "and", "non", "sensu", "emend"];
+
+/**
+ * Splits a scientific name: "× Genus (Subgenus) species rank. infraspecies Authorship, year".
+ * Epithets must start with a lowercase letter, the first word that is not an epithet starts the authorship,
+ * which lasts until the next rank marker. Author particles followed by a capitalized word ("van der Wulp")
+ * start it too. Hybrid formulas ("Quercus robur × petraea") keep their first parent only.
+ * Anything that does not start with a word is not a name, its canonical form is the input with whitespace collapsed.
+ * Names written in capitals only are parsed lowercased, an authorship without punctuation is then read as epithets.
+ */
+pub fn parse(input: &str) -> ParsedName {
+    return parse_name(input, false);
+}
+
+/**
+ * Same as parse, for what users type rather than what datasets hold: they don't mind case, so a capitalized word
+ * right after the genus is the specific epithet ("Quercus Robur", "Puma C"), unless it starts an author's name
+ * with a particle. Words with punctuation or digits ("L.", "1771") are still authorship.
+ */
+pub fn parse_query(input: &str) -> ParsedName {
+    return parse_name(input, true);
+}
+
+fn parse_name(input: &str, query: bool) -> ParsedName {
+    let lowercased;
+    let input = match input.chars().any(char::is_lowercase) {
+        true => input,
+        false => {
+            lowercased = input.to_lowercase();
+            &lowercased
+        },
+    };
+    let tokens = input.split_whitespace().collect::<Vec<&str>>();
+    let mut name = ParsedName::default();
+
+    let mut index = 0;
+    // nothogenus, "× Agropogon" or "×Agropogon"
+    if tokens.len() > 1 && is_hybrid_sign(tokens[0]) && is_word(tokens[1]) {
+        name.hybrid = true;
+        index = 1;
+    }
+    let uninomial = match tokens.get(index) {
+        Some(token) => strip_hybrid_sign(token, &mut name.hybrid),
+        None => "",
+    };
+    if !is_word(uninomial) {
+        name.canonical = tokens.join(" ");
+        return name;
+    }
+    name.uninomial = Some(uninomial.to_owned());
+    index += 1;
+
+    // authorship of the last epithet seen
+    let mut authorship: Vec<&str> = vec![];
+    while index < tokens.len() {
+        let token = tokens[index];
+        let next = tokens.get(index + 1).copied();
+
+        if let (Some((rank, infrageneric)), Some(next)) = (rank_marker(token), next) {
+            if infrageneric && name.specific_epithet.is_none() && name.infrageneric_epithet.is_none() && is_capitalized_word(next) {
+                name.infrageneric_epithet = Some(Epithet { epithet: next.to_owned(), rank: Some(rank.to_owned()) });
+                authorship.clear();
+                index += 2;
+                continue;
+            }
+            if !infrageneric && name.specific_epithet.is_some() && is_epithet(next) {
+                name.infraspecific_epithets.push(Epithet { epithet: next.to_owned(), rank: Some(rank.to_owned()) });
+                authorship.clear();
+                index += 2;
+                continue;
+            }
+        }
+
+        if authorship.is_empty() {
+            if is_hybrid_sign(token) {
+                name.hybrid = true;
+                // a formula, the second parent is not part of this name
+                if name.specific_epithet.is_some() {
+                    break;
+                }
+                index += 1;
+                continue;
+            }
+            if let Some(subgenus) = token.strip_prefix('(').and_then(|token| token.strip_suffix(')')) {
+                if name.specific_epithet.is_none() && name.infrageneric_epithet.is_none() && is_capitalized_word(subgenus) {
+                    name.infrageneric_epithet = Some(Epithet { epithet: subgenus.to_owned(), rank: None });
+                    index += 1;
+                    continue;
+                }
+            }
+            let epithet = strip_hybrid_sign(token, &mut name.hybrid);
+            let capitalized_epithet = query && name.specific_epithet.is_none() && is_capitalized_word(epithet);
+            if (is_epithet(epithet) || capitalized_epithet) && !starts_with_author(&tokens[index..]) {
+                match name.specific_epithet {
+                    None => name.specific_epithet = Some(epithet.to_owned()),
+                    Some(_) => name.infraspecific_epithets.push(Epithet { epithet: epithet.to_owned(), rank: None }),
+                }
+                index += 1;
+                continue;
+            }
+        }
+
+        authorship.push(token);
+        index += 1;
+    }
+
+    if !authorship.is_empty() {
+        let authorship = authorship.join(" ");
+        name.year = find_year(&authorship);
+        name.authorship = Some(authorship);
+    }
+    name.canonical = canonical_form(&name);
+    return name;
+}
+
+/**
+ * "E. coli" or "E.coli": the initial of an abbreviated genus, and the rest of the name, which starts with an epithet.
+ */
+pub fn genus_abbreviation(input: &str) -> Option<(char, &str)> {
+    let input = input.trim_start();
+    let mut chars = input.chars();
+    let initial = chars.next().filter(|initial| initial.is_uppercase())?;
+    if chars.next() != Some('.') {
+        return None;
+    }
+    let rest = input[initial.len_utf8() + 1..].trim_start();
+    if !rest.split_whitespace().next().is_some_and(is_epithet) {
+        return None;
+    }
+    return Some((initial, rest));
+}
+
+// the canonical form alone, what names are matched on when match_on is "canonical"
+pub fn canonical(input: &str) -> String {
+    return parse(input).canonical;
+}
+
+// same as canonical, for queries, see parse_query
+pub fn canonical_query(input: &str) -> String {
+    return parse_query(input).canonical;
+}
+
+/*
+    The infrageneric epithet is only part of the canonical form of names above the species, in parentheses,
+    so that parsing a canonical form gives it back unchanged: queries and haystacks can be canonicalized twice.
+ */
+fn canonical_form(name: &ParsedName) -> String {
+    let mut parts = name.uninomial.iter().cloned().collect::<Vec<String>>();
+    match &name.specific_epithet {
+        Some(specific_epithet) => parts.push(specific_epithet.clone()),
+        None => parts.extend(name.infrageneric_epithet.iter().map(|epithet| format!("({})", epithet.epithet))),
+    }
+    parts.extend(name.infraspecific_epithets.iter().map(|epithet| epithet.epithet.clone()));
+    return parts.join(" ");
+}
+
+fn is_hybrid_sign(token: &str) -> bool {
+    return token == "×" || token == "x" || token == "X";
+}
+
+// "×piperita" is "piperita", and the name is a hybrid
+fn strip_hybrid_sign<'a>(token: &'a str, hybrid: &mut bool) -> &'a str {
+    return match token.strip_prefix(HYBRID_SIGN) {
+        Some(rest) if !rest.is_empty() => {
+            *hybrid = true;
+            rest
+        },
+        _ => token,
+    };
+}
+
+// letters and hyphens, "novae-angliae"
+fn is_word(token: &str) -> bool {
+    return token.chars().next().is_some_and(char::is_alphabetic)
+        && token.chars().all(|c| c.is_alphabetic() || c == '-');
+}
+
+fn is_epithet(token: &str) -> bool {
+    return is_word(token) && token.chars().next().is_some_and(char::is_lowercase);
+}
+
+// particles, then a capitalized word
+fn starts_with_author(tokens: &[&str]) -> bool {
+    let particles = tokens.iter().take_while(|token| AUTHOR_PARTICLES.contains(&token.to_lowercase().as_str())).count();
+    return particles > 0 && tokens.get(particles).is_some_and(|token| token.chars().next().is_some_and(char::is_uppercase));
+}
+
+fn is_capitalized_word(token: &str) -> bool {
+    return is_word(token) && token.chars().next().is_some_and(char::is_uppercase);
+}
+
+// normalized marker and whether it is infrageneric, the final dot is optional
+fn rank_marker(token: &str) -> Option<(&'static str, bool)> {
+    let written = token.strip_suffix('.').unwrap_or(token).to_lowercase();
+    return RANK_MARKERS.iter()
+        .find(|(marker, _, _)| *marker == written)
+        .map(|(_, rank, infrageneric)| (*rank, *infrageneric));
+}
+
+// the first standalone 4 digit number that can be a publication year
+fn find_year(authorship: &str) -> Option<u16> {
+    return authorship
+        .split(|c: char| !c.is_ascii_digit())
+        .filter(|digits| digits.len() == 4)
+        .filter_map(|digits| digits.parse::<u16>().ok())
+        .find(|year| (1500..=2100).contains(year));
+}
+
+/**
+ * What homonyms are told apart with: the names of the authors, lowercased ascii, and the year.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct Authorship {
+    authors: Vec<String>,
+    year: Option<u16>,
+}
+
+impl Authorship {
+    pub fn of(name: &ParsedName) -> Self {
+        let authors = match &name.authorship {
+            Some(authorship) => deunicode::deunicode(authorship).to_lowercase()
+                .split(|c: char| !c.is_ascii_alphabetic())
+                .filter(|word| !word.is_empty() && !AUTHORSHIP_CONNECTORS.contains(word))
+                .map(str::to_owned)
+                .collect(),
+            None => vec![],
+        };
+        return Authorship { authors, year: name.year };
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.authors.is_empty() && self.year.is_none();
+    }
+
+    /**
+     * How far the authorship of a candidate is from this one, lower is closer: first the number of authors
+     * missing from the candidate's, abbreviations matching ("L." is found in "Linnaeus"), then the number of years apart.
+     */
+    pub fn distance(&self, candidate: &Authorship) -> (usize, u16) {
+        let missing_authors = self.authors.iter()
+            .filter(|author| !candidate.authors.iter().any(|other| other.starts_with(author.as_str()) || author.starts_with(other.as_str())))
+            .count();
+        let years_apart = match (self.year, candidate.year) {
+            (Some(year), Some(other)) => year.abs_diff(other),
+            (Some(_), None) => u16::MAX,
+            (None, _) => 0,
+        };
+        return (missing_authors, years_apart);
+    }
+}
+
+/**
+ * The limit first results of ranked, except that results sharing a key (homonyms, when the key is their canonical name)
+ * are grouped at the place of the best ranked one, the closest to the authorship of the query first.
+ * Homonyms of the last result are taken past limit, as they can win the tie-break.
+ */
+pub fn tie_break<'a, K: PartialEq>(query: &Authorship, ranked: impl Iterator<Item = (K, &'a EngineInputData)>, limit: usize) -> Vec<EngineInputData> {
+    if query.is_empty() {
+        return ranked.take(limit).map(|(_, item)| item.clone()).collect();
+    }
+
+    let mut ranked = ranked.peekable();
+    let mut window = ranked.by_ref().take(limit).collect::<Vec<(K, &EngineInputData)>>();
+    while let Some((last_key, _)) = window.last() {
+        match ranked.next_if(|(key, _)| key == last_key) {
+            Some(homonym) => window.push(homonym),
+            None => break,
+        }
+    }
+
+    let mut tie_broken = window.iter().enumerate()
+        .map(|(position, (key, item))| {
+            let group = window.iter().position(|(other, _)| other == key).unwrap_or(position);
+            let distance = query.distance(&Authorship::of(&parse(&item.string)));
+            return (group, distance, position, *item);
+        })
+        .collect::<Vec<(usize, (usize, u16), usize, &EngineInputData)>>();
+    tie_broken.sort_by_key(|(group, distance, position, _)| (*group, *distance, *position));
+    return tie_broken.into_iter()
+        .take(limit)
+        .map(|(_, _, _, item)| item.clone())
+        .collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{canonical, canonical_query, genus_abbreviation, parse, parse_query, tie_break, Authorship};
+    use crate::io::{test_items, EngineInputData};
+
+    fn epithets(input: &str) -> Vec<(String, Option<String>)> {
+        return parse(input).infraspecific_epithets.into_iter()
+            .map(|epithet| (epithet.epithet, epithet.rank))
+            .collect();
+    }
+
+    #[test]
+    fn binomial_with_authorship() {
+        let name = parse("Puma concolor (Linnaeus, 1771)");
+        assert_eq!(name.uninomial.as_deref(), Some("Puma"));
+        assert_eq!(name.specific_epithet.as_deref(), Some("concolor"));
+        assert_eq!(name.authorship.as_deref(), Some("(Linnaeus, 1771)"));
+        assert_eq!(name.year, Some(1771));
+        assert!(!name.hybrid);
+        assert_eq!(name.canonical, "Puma concolor");
+    }
+
+    #[test]
+    fn hybrids() {
+        let name = parse("× Agropogon");
+        assert!(name.hybrid);
+        assert_eq!(name.uninomial.as_deref(), Some("Agropogon"));
+        assert_eq!(name.canonical, "Agropogon");
+        assert_eq!(canonical("×Agropogon"), "Agropogon");
+
+        // formula, the second parent is dropped
+        let name = parse("Quercus robur × petraea");
+        assert!(name.hybrid);
+        assert!(name.infraspecific_epithets.is_empty());
+        assert_eq!(name.canonical, "Quercus robur");
+
+        let name = parse("Mentha ×piperita L.");
+        assert!(name.hybrid);
+        assert_eq!(name.canonical, "Mentha piperita");
+    }
+
+    #[test]
+    fn infraspecific_rank() {
+        let name = parse("Rosa canina L. var. dumalis");
+        assert_eq!(name.canonical, "Rosa canina dumalis");
+        assert_eq!(epithets("Rosa canina L. var. dumalis"), vec![("dumalis".to_owned(), Some("var.".to_owned()))]);
+        // the authorship is the one of the last epithet
+        assert_eq!(name.authorship, None);
+
+        assert_eq!(epithets("Poa annua ssp. annua"), vec![("annua".to_owned(), Some("subsp.".to_owned()))]);
+        // zoological trinomial, no rank marker
+        assert_eq!(epithets("Canis lupus familiaris Linnaeus, 1758"), vec![("familiaris".to_owned(), None)]);
+    }
+
+    #[test]
+    fn infrageneric_epithet() {
+        let name = parse("Quercus (Lepidobalanus) robur");
+        assert_eq!(name.infrageneric_epithet.as_ref().map(|epithet| epithet.epithet.as_str()), Some("Lepidobalanus"));
+        assert_eq!(name.canonical, "Quercus robur");
+
+        // above the species, the infrageneric epithet is kept, and parsing the canonical form gives it back
+        let name = parse("Quercus subg. Lepidobalanus");
+        assert_eq!(name.infrageneric_epithet.as_ref().and_then(|epithet| epithet.rank.as_deref()), Some("subg."));
+        assert_eq!(name.canonical, "Quercus (Lepidobalanus)");
+        assert_eq!(canonical(&name.canonical), "Quercus (Lepidobalanus)");
+    }
+
+    #[test]
+    fn author_particles() {
+        let name = parse("Aus bus van der Wulp, 1868");
+        assert_eq!(name.canonical, "Aus bus");
+        assert_eq!(name.authorship.as_deref(), Some("van der Wulp, 1868"));
+        assert_eq!(name.year, Some(1868));
+
+        assert_eq!(canonical("Aus bus de Candolle"), "Aus bus");
+        assert_eq!(canonical("Aus bus cus von Linstow"), "Aus bus cus");
+        assert_eq!(canonical("Aus bus de la Torre"), "Aus bus");
+        assert_eq!(canonical("Aus bus le Conte"), "Aus bus");
+        assert_eq!(canonical("Aus bus d' Orbigny"), "Aus bus");
+        assert_eq!(canonical("Aus bus d'Orbigny"), "Aus bus");
+        // not followed by an author, still epithets
+        assert_eq!(canonical("Aus bus la"), "Aus bus la");
+    }
+
+    #[test]
+    fn capitalized_epithet_in_queries() {
+        assert_eq!(canonical_query("Quercus Robur"), "Quercus Robur");
+        assert_eq!(canonical_query("quercus  Robur"), "quercus Robur");
+        assert_eq!(canonical_query("Puma C"), "Puma C");
+        assert_eq!(canonical_query("Quercus (Lepidobalanus) Robur"), "Quercus Robur");
+        // only right after the genus
+        assert_eq!(canonical_query("Puma concolor Linnaeus"), "Puma concolor");
+
+        let query = parse_query("Quercus Robur L.");
+        assert_eq!(query.canonical, "Quercus Robur");
+        assert_eq!(query.authorship.as_deref(), Some("L."));
+
+        // what looks like an author is still authorship
+        assert_eq!(canonical_query("Quercus L."), "Quercus");
+        assert_eq!(canonical_query("Aus Van der Wulp"), "Aus");
+        assert_eq!(canonical_query("Aus (Wulp, 1868)"), "Aus");
+
+        // names of the dataset are not queries
+        assert_eq!(canonical("Quercus Robur"), "Quercus");
+    }
+
+    #[test]
+    fn capitals_only() {
+        assert_eq!(canonical("PUMA CONCOLOR"), "puma concolor");
+        let name = parse("PUMA CONCOLOR (LINNAEUS, 1771)");
+        assert_eq!(name.canonical, "puma concolor");
+        assert_eq!(name.authorship.as_deref(), Some("(linnaeus, 1771)"));
+        assert_eq!(name.year, Some(1771));
+    }
+
+    #[test]
+    fn not_names() {
+        let name = parse("");
+        assert_eq!(name.uninomial, None);
+        assert_eq!(name.canonical, "");
+        assert_eq!(canonical("   "), "");
+        assert_eq!(canonical(" 1234  abc "), "1234 abc");
+        // no space after the dot, the abbreviation is one token that is not a word
+        assert_eq!(canonical("E.coli"), "E.coli");
+    }
+
+    #[test]
+    fn abbreviated_genus() {
+        assert_eq!(genus_abbreviation("E. coli"), Some(('E', "coli")));
+        assert_eq!(genus_abbreviation("E.coli"), Some(('E', "coli")));
+        assert_eq!(genus_abbreviation(" Q. robur L."), Some(('Q', "robur L.")));
+        assert_eq!(genus_abbreviation("Escherichia coli"), None);
+        assert_eq!(genus_abbreviation("E. Coli"), None);
+        assert_eq!(genus_abbreviation("e. coli"), None);
+        assert_eq!(genus_abbreviation("E."), None);
+    }
+
+    #[test]
+    fn authorship_distance() {
+        let query = Authorship::of(&parse("Puma concolor L., 1771"));
+        assert_eq!(query.distance(&Authorship::of(&parse("Puma concolor (Linnaeus, 1771)"))), (0, 0));
+        assert_eq!(query.distance(&Authorship::of(&parse("Puma concolor Smith, 1800"))), (1, 29));
+        assert_eq!(query.distance(&Authorship::of(&parse("Puma concolor"))), (1, u16::MAX));
+
+        let no_authorship = Authorship::of(&parse("Puma concolor"));
+        assert!(no_authorship.is_empty());
+        assert_eq!(no_authorship.distance(&Authorship::of(&parse("Puma concolor Smith, 1800"))), (0, 0));
+        // connectors don't count as authors
+        let query = Authorship::of(&parse("Aus bus Smith ex Jones"));
+        assert_eq!(query.distance(&Authorship::of(&parse("Aus bus Smith & Jones"))), (0, 0));
+    }
+
+    #[test]
+    fn homonyms_tie_broken_by_authorship() {
+        let items = test_items(&["Aus bus Smith, 1800", "Aus bus Jones, 1850", "Aus cus Jones, 1850"]);
+        let ranked = || items.iter().map(|item| (canonical(&item.string), item));
+        let strings = |results: Vec<EngineInputData>| results.into_iter().map(|item| item.string).collect::<Vec<String>>();
+
+        let query = Authorship::of(&parse("Aus bus Jones"));
+        assert_eq!(strings(tie_break(&query, ranked(), 3)), vec!["Aus bus Jones, 1850", "Aus bus Smith, 1800", "Aus cus Jones, 1850"]);
+        // homonyms of the last result are considered past limit
+        assert_eq!(strings(tie_break(&query, ranked(), 1)), vec!["Aus bus Jones, 1850"]);
+        // no authorship, the order is kept
+        assert_eq!(strings(tie_break(&Authorship::default(), ranked(), 2)), vec!["Aus bus Smith, 1800", "Aus bus Jones, 1850"]);
+    }
+}
@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+
+use crate::io::EngineInputData;
+
+pub const NORMALIZATION_STEPS: [&str; 5] = ["trim", "collapse_whitespace", "case_fold", "deunicode", "strip_trailing_punctuation"];
+// only sentence punctuation, closing brackets and quotes are part of names
+const TRAILING_PUNCTUATION: [char; 6] = ['.', ',', ';', ':', '!', '?'];
+
+/**
+ * Computes the keys of the normalized exact index, from the steps enabled in the config (see NORMALIZATION_STEPS).
+ * Steps are always applied in the same order, whatever the order they are configured in:
+ * deunicode, case_fold, strip_trailing_punctuation, collapse_whitespace, trim.
+ */
+#[derive(Debug, Clone)]
+pub struct Normalizer {
+    deunicode: bool,
+    case_fold: bool,
+    strip_trailing_punctuation: bool,
+    collapse_whitespace: bool,
+    trim: bool,
+}
+
+impl Normalizer {
+    // steps were checked when loading the config
+    pub fn new(steps: &[String]) -> Self {
+        let has = |step: &str| steps.iter().any(|configured| configured == step);
+        return Normalizer {
+            deunicode: has("deunicode"),
+            case_fold: has("case_fold"),
+            strip_trailing_punctuation: has("strip_trailing_punctuation"),
+            collapse_whitespace: has("collapse_whitespace"),
+            trim: has("trim"),
+        };
+    }
+
+    pub fn normalize(&self, input: &str) -> String {
+        let mut result = match self.deunicode {
+            true => deunicode::deunicode(input),
+            false => input.to_owned(),
+        };
+        if self.case_fold {
+            result = result.to_lowercase();
+        }
+        if self.strip_trailing_punctuation {
+            // along with the whitespace around it, "Quercus robur . " becomes "Quercus robur"
+            let stripped_len = result.trim_end_matches(|c: char| TRAILING_PUNCTUATION.contains(&c) || c.is_whitespace()).len();
+            if result[stripped_len..].contains(TRAILING_PUNCTUATION) {
+                result.truncate(stripped_len);
+            }
+        }
+        if self.collapse_whitespace {
+            // each run of whitespace becomes a single space, leading and trailing ones too, trimming is a separate step
+            let mut collapsed = String::with_capacity(result.len());
+            let mut previous_whitespace = false;
+            for c in result.chars() {
+                if c.is_whitespace() {
+                    if !previous_whitespace {
+                        collapsed.push(' ');
+                    }
+                    previous_whitespace = true;
+                } else {
+                    collapsed.push(c);
+                    previous_whitespace = false;
+                }
+            }
+            result = collapsed;
+        }
+        if self.trim {
+            result = result.trim().to_owned();
+        }
+        return result;
+    }
+}
+
+/**
+ * Exact index on normalized strings. Items whose strings normalize the same are ambiguous, the first one in the dataset is kept.
+ */
+pub fn to_normalized_hashmap(input_data_vec: &[EngineInputData], normalizer: &Normalizer) -> HashMap<String, EngineInputData> {
+    let mut result: HashMap<String, EngineInputData> = HashMap::new();
+
+    for input_data in input_data_vec.iter() {
+        result.entry(normalizer.normalize(&input_data.string)).or_insert_with(|| input_data.clone());
+    }
+
+    return result;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{to_normalized_hashmap, Normalizer, NORMALIZATION_STEPS};
+    use crate::io::EngineInputData;
+
+    fn all_steps() -> Normalizer {
+        return Normalizer::new(&NORMALIZATION_STEPS.map(str::to_owned));
+    }
+
+    fn steps(steps: &[&str]) -> Normalizer {
+        return Normalizer::new(&steps.iter().map(|step| step.to_string()).collect::<Vec<String>>());
+    }
+
+    #[test]
+    fn same_name_same_key() {
+        let normalizer = all_steps();
+        let key = normalizer.normalize("Quercus robur");
+        assert_eq!(key, "quercus robur");
+        assert_eq!(normalizer.normalize("quercus  Robur"), key);
+        assert_eq!(normalizer.normalize("Quercus robur "), key);
+        assert_eq!(normalizer.normalize("Quércus robur"), key);
+        assert_eq!(normalizer.normalize("Quercus robur . "), key);
+        assert_eq!(normalizer.normalize("\tQuercus\nrobur;"), key);
+    }
+
+    #[test]
+    fn steps_apply_in_fixed_order() {
+        let input = " Quércus  Robur , ";
+        assert_eq!(steps(&["trim", "deunicode", "case_fold"]).normalize(input), "quercus  robur ,");
+        assert_eq!(steps(&["case_fold", "deunicode", "trim"]).normalize(input), "quercus  robur ,");
+        // punctuation is stripped before whitespace is collapsed, so no trailing space is left to trim
+        assert_eq!(steps(&["collapse_whitespace", "strip_trailing_punctuation"]).normalize(input), " Quércus Robur");
+        assert_eq!(steps(&[]).normalize(input), input);
+    }
+
+    #[test]
+    fn trailing_punctuation() {
+        let normalizer = steps(&["strip_trailing_punctuation"]);
+        assert_eq!(normalizer.normalize("Quercus robur . "), "Quercus robur");
+        assert_eq!(normalizer.normalize("Quercus robur.;"), "Quercus robur");
+        // whitespace alone is left to trim
+        assert_eq!(normalizer.normalize("Quercus robur "), "Quercus robur ");
+        // brackets and quotes are part of names
+        assert_eq!(normalizer.normalize("Quercus robur (L.)"), "Quercus robur (L.)");
+        assert_eq!(normalizer.normalize("Quercus 'robur'"), "Quercus 'robur'");
+    }
+
+    #[test]
+    fn collisions_keep_first() {
+        let items = ["Quercus robur", "quercus  ROBUR", "Quercus petraea"].iter().enumerate()
+            .map(|(index, string)| EngineInputData { string: string.to_string(), data: serde_json::json!(index) })
+            .collect::<Vec<EngineInputData>>();
+        let index = to_normalized_hashmap(&items, &all_steps());
+        assert_eq!(index.len(), 2);
+        assert_eq!(index["quercus robur"].string, "Quercus robur");
+        assert_eq!(index["quercus petraea"].data, serde_json::json!(2));
+    }
+}
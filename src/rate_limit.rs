@@ -0,0 +1,183 @@
+use std::{collections::HashMap, time::{Duration, Instant}};
+
+use axum::{extract::{ConnectInfo, MatchedPath, Request, State}, http::HeaderMap, middleware::Next, response::Response};
+use parking_lot::Mutex;
+use uuid::Uuid;
+
+use crate::{auth::{ApiKey, PUBLIC_ROUTES}, routes::error::ApiError, AppState};
+
+const FORWARDED_FOR_HEADER: &str = "x-forwarded-for";
+
+/**
+ * Token bucket: starts full, each request takes a token, tokens come back continuously at refill_per_sec.
+ */
+#[derive(Debug)]
+pub struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    refill_per_sec: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(capacity: u32, refill_per_sec: f64) -> Self {
+        return TokenBucket {
+            capacity: capacity as f64,
+            tokens: capacity as f64,
+            refill_per_sec,
+            last_refill: Instant::now(),
+        };
+    }
+
+    // a bucket allowing requests_per_minute requests, all of them possibly at once
+    pub fn per_minute(requests_per_minute: u32) -> Self {
+        return TokenBucket::new(requests_per_minute, requests_per_minute as f64 / 60.0);
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    /**
+     * Takes a token if there is one, otherwise returns how long until the next one.
+     */
+    pub fn try_take(&mut self) -> Result<(), Duration> {
+        self.refill();
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        return Err(Duration::from_secs_f64((1.0 - self.tokens) / self.refill_per_sec));
+    }
+}
+
+/**
+ * Identifies the client a request comes from: the API key name when authenticated, otherwise the IP address.
+ * Added to the request extensions by limit_clients.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ClientId(String);
+
+/**
+ * One token bucket per client, created on first request.
+ * Buckets that filled up again are dropped by prune(), they are the same as new ones.
+ */
+pub struct ClientLimiter {
+    requests_per_minute: u32,
+    burst: u32,
+    buckets: Mutex<HashMap<ClientId, TokenBucket>>,
+}
+
+impl ClientLimiter {
+    pub fn new(requests_per_minute: u32, burst: u32) -> Self {
+        return ClientLimiter { requests_per_minute, burst, buckets: Mutex::new(HashMap::new()) };
+    }
+
+    fn try_take(&self, client: &ClientId) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock();
+        let bucket = buckets.entry(client.clone())
+            .or_insert_with(|| TokenBucket::new(self.burst, self.requests_per_minute as f64 / 60.0));
+        return bucket.try_take();
+    }
+
+    pub fn prune(&self) {
+        self.buckets.lock().retain(|_, bucket| {
+            bucket.refill();
+            return bucket.tokens < bucket.capacity;
+        });
+    }
+}
+
+/**
+ * Autocomplete engines held by each client, whether attributed to a session or to a websocket.
+ * Indexed by engine uuid, as that is all the cleanup handler knows when it reclaims an engine.
+ * A client holds few engines and pools are small, so counting by scanning is fine.
+ */
+pub struct ClientEngines {
+    max_per_client: usize,
+    holders: Mutex<HashMap<Uuid, ClientId>>,
+}
+
+impl ClientEngines {
+    pub fn new(max_per_client: usize) -> Self {
+        return ClientEngines { max_per_client, holders: Mutex::new(HashMap::new()) };
+    }
+
+    /**
+     * Records that the client holds the engine, unless it already holds the maximum. Returns false in that case.
+     */
+    pub fn try_hold(&self, client: &ClientId, uuid: Uuid) -> bool {
+        let mut holders = self.holders.lock();
+        if holders.values().filter(|holder| *holder == client).count() >= self.max_per_client {
+            return false;
+        }
+        holders.insert(uuid, client.clone());
+        return true;
+    }
+
+    pub fn release(&self, uuid: &Uuid) {
+        self.holders.lock().remove(uuid);
+    }
+
+    // the engine is now stored under another uuid, by the same client
+    pub fn rename(&self, old_uuid: &Uuid, new_uuid: Uuid) {
+        let mut holders = self.holders.lock();
+        if let Some(client) = holders.remove(old_uuid) {
+            holders.insert(new_uuid, client);
+        }
+    }
+
+    pub fn max_per_client(&self) -> usize {
+        return self.max_per_client;
+    }
+}
+
+/**
+ * Middleware identifying the client of each request, and rate limiting it if configured.
+ * Runs after authentication: an authenticated client is its key, and keys with their own quota
+ * were already limited by it.
+ */
+pub async fn limit_clients(
+    State(appstate): State<AppState>,
+    ConnectInfo(remote_addr): ConnectInfo<std::net::SocketAddr>,
+    matched_path: Option<MatchedPath>,
+    mut request: Request,
+    next: Next,
+    )
+-> Result<Response, ApiError> {
+    let api_key = request.extensions().get::<std::sync::Arc<ApiKey>>().cloned();
+    let client = match &api_key {
+        Some(api_key) => ClientId(format!("key:{}", api_key.name)),
+        None => {
+            let ip = match appstate.server_config.trust_forwarded_for {
+                true => forwarded_for(request.headers()).unwrap_or_else(|| remote_addr.ip().to_string()),
+                false => remote_addr.ip().to_string(),
+            };
+            ClientId(format!("ip:{}", ip))
+        },
+    };
+
+    let is_public = matched_path.is_some_and(|matched_path| PUBLIC_ROUTES.contains(&matched_path.as_str()));
+    let has_own_quota = api_key.is_some_and(|api_key| api_key.has_quota());
+    if let (Some(client_limiter), false, false) = (&appstate.client_limiter, is_public, has_own_quota) {
+        if let Err(retry_after) = client_limiter.try_take(&client) {
+            tracing::info!(client = ?client, "Client rate limited");
+            appstate.metrics.client_rejections.with_label_values(&["rate_limited"]).inc();
+            return Err(ApiError::rate_limited(retry_after));
+        }
+    }
+
+    request.extensions_mut().insert(client);
+    return Ok(next.run(request).await);
+}
+
+// the last address of the header, the one added by the reverse proxy in front of us
+fn forwarded_for(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get(FORWARDED_FOR_HEADER)?.to_str().ok()?;
+    let last = value.rsplit(',').next()?.trim();
+    return last.parse::<std::net::IpAddr>().ok().map(|ip| ip.to_string());
+}
@@ -0,0 +1,182 @@
+use std::time::Duration;
+
+use axum::{extract::{rejection::{JsonRejection, QueryRejection}, FromRequest, FromRequestParts}, http::{header, StatusCode}, response::{IntoResponse, Response}, Json};
+use deadpool::unmanaged::PoolError;
+use serde::Serialize;
+
+/**
+ * Error returned by every route, sent as a problem details body (RFC 9457).
+ * code is stable and meant to be matched on by clients, detail is for humans and can change.
+ */
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    code: &'static str,
+    detail: String,
+    // sent as the Retry-After header, in seconds
+    retry_after: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct ProblemDetails {
+    #[serde(rename = "type")]
+    problem_type: &'static str,
+    title: &'static str,
+    status: u16,
+    detail: String,
+    code: &'static str,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: &'static str, detail: impl Into<String>) -> Self {
+        return ApiError { status, code, detail: detail.into(), retry_after: None };
+    }
+
+    pub fn empty_query() -> Self {
+        return ApiError::new(StatusCode::BAD_REQUEST, "empty_query", "The query is empty");
+    }
+
+    pub fn too_many_fields(max_fields: usize) -> Self {
+        return ApiError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "too_many_fields",
+            format!("This session already uses the maximum of {} autocomplete fields", max_fields));
+    }
+
+    pub fn batch_too_large(batch_size: usize, max_batch_size: usize) -> Self {
+        return ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "batch_too_large",
+            format!("{} strings were sent, at most {} are accepted per request", batch_size, max_batch_size));
+    }
+
+    pub fn too_many_engines(max_engines: usize) -> Self {
+        return ApiError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "too_many_engines",
+            format!("This client already holds the maximum of {} autocomplete engines", max_engines));
+    }
+
+    // index of the string in the batch, if any
+    pub fn string_too_long(index: Option<usize>, max_length: usize) -> Self {
+        let detail = match index {
+            Some(index) => format!("String {} is longer than the maximum of {} characters", index, max_length),
+            None => format!("The string is longer than the maximum of {} characters", max_length),
+        };
+        return ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "string_too_long", detail);
+    }
+
+    pub fn too_many_results(requested: u32, max_results: usize) -> Self {
+        return ApiError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "too_many_results",
+            format!("n_first_results is {}, at most {} results per string are returned", requested, max_results));
+    }
+
+    pub fn invalid_csv(detail: String) -> Self {
+        return ApiError::new(StatusCode::BAD_REQUEST, "invalid_csv", detail);
+    }
+
+    pub fn unsupported_csv_type() -> Self {
+        return ApiError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "unsupported_media_type",
+            "The content type must be text/csv or text/tab-separated-values");
+    }
+
+    pub fn job_storage_error(detail: String) -> Self {
+        tracing::error!(error = detail, "Job storage error");
+        return ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "job_storage_error", "The job could not be read or saved");
+    }
+
+    pub fn missing_api_key() -> Self {
+        return ApiError::new(StatusCode::UNAUTHORIZED, "missing_api_key", "An API key is required, in the x-api-key header");
+    }
+
+    pub fn invalid_api_key() -> Self {
+        return ApiError::new(StatusCode::UNAUTHORIZED, "invalid_api_key", "The API key is not valid");
+    }
+
+    pub fn route_not_allowed(key_name: &str, route: &str) -> Self {
+        return ApiError::new(StatusCode::FORBIDDEN, "route_not_allowed", format!("The key {:?} can't call {}", key_name, route));
+    }
+
+    pub fn rate_limited(retry_after: Duration) -> Self {
+        // rounded up, retrying before would be rejected again
+        let retry_after = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+        let mut error = ApiError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "rate_limited",
+            format!("Too many requests, retry in {} seconds", retry_after));
+        error.retry_after = Some(retry_after);
+        return error;
+    }
+
+    pub fn session_expired() -> Self {
+        return ApiError::new(
+            StatusCode::NOT_FOUND,
+            "session_expired",
+            "The session token is unknown or expired, send an empty token to start a new session");
+    }
+
+    pub fn session_error(e: tower_sessions::session::Error) -> Self {
+        tracing::error!(error = %e, "Session error");
+        return ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "session_error", "The session could not be read or saved");
+    }
+}
+
+impl From<PoolError> for ApiError {
+    fn from(e: PoolError) -> Self {
+        tracing::warn!(error = %e, "EnginePool error");
+        return match e {
+            PoolError::Timeout => ApiError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "pool_exhausted",
+                "No search engine available at the moment, retry later"),
+            _ => ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "pool_unavailable", e.to_string()),
+        };
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE {
+            return ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, "body_too_large", rejection.body_text());
+        }
+        return ApiError::new(rejection.status(), "invalid_body", rejection.body_text());
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> Self {
+        return ApiError::new(rejection.status(), "invalid_query", rejection.body_text());
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = ProblemDetails {
+            problem_type: "about:blank",
+            title: self.status.canonical_reason().unwrap_or("Error"),
+            status: self.status.as_u16(),
+            detail: self.detail,
+            code: self.code,
+        };
+
+        let mut response = (self.status, [(header::CONTENT_TYPE, "application/problem+json")], Json(body)).into_response();
+        if let Some(retry_after) = self.retry_after {
+            response.headers_mut().insert(header::RETRY_AFTER, retry_after.into());
+        }
+        return response;
+    }
+}
+
+// same as axum::Json, but malformed bodies are rejected with an ApiError
+#[derive(FromRequest)]
+#[from_request(via(axum::Json), rejection(ApiError))]
+pub struct ApiJson<T>(pub T);
+
+// same as axum::extract::Query, but malformed query strings are rejected with an ApiError
+#[derive(FromRequestParts)]
+#[from_request(via(axum::extract::Query), rejection(ApiError))]
+pub struct ApiQuery<T>(pub T);
@@ -1,34 +1,66 @@
-use axum::{Json, http::StatusCode, extract::State};
+use std::sync::Arc;
+
+use axum::{Extension, Json, extract::State};
 //use axum_macros::debug_handler;
 use serde::{Deserialize, Serialize};
 
-use crate::{io::EngineInputData, AppState};
+use crate::{auth::ApiKey, io::EngineInputData, AppState};
+
+use super::{error::{ApiError, ApiJson}, limits};
 
 
 
 #[derive(Debug, Deserialize)]
 pub struct ExactMatchRequest {
     strings: Vec<String>,
+    // falls back to the normalized exact index when the string itself is not found
+    #[serde(default)]
+    normalize: bool,
+}
+
+// which key matched, the string as sent or its normalized form
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ExactHit {
+    Raw,
+    Normalized,
 }
 
 // the output response
 #[derive(Serialize)]
 pub struct ExactMatchResponse {
-    matches: Vec<Option<EngineInputData>>
+    matches: Vec<Option<EngineInputData>>,
+    // same order as matches, null when nothing matched
+    hits: Vec<Option<ExactHit>>,
 }
 
 pub async fn exact_match(
     State(appstate): State<AppState>, 
-    Json(payload): Json<ExactMatchRequest>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    ApiJson(payload): ApiJson<ExactMatchRequest>,
     )
--> (StatusCode, Json<ExactMatchResponse>) {
+-> Result<Json<ExactMatchResponse>, ApiError> {
 
     let input_vec = payload.strings;
+    limits::check_batch(&appstate, &api_key, &input_vec)?;
 
     let mut result: Vec<Option<EngineInputData>> = Vec::new();
+    let mut hits: Vec<Option<ExactHit>> = Vec::new();
     for s in input_vec {
-        result.push(appstate.db_hashmap.get(&s).cloned());
+        let (string_res, hit) = match appstate.db_hashmap.get(&s) {
+            Some(item) => (Some(item.clone()), Some(ExactHit::Raw)),
+            None if payload.normalize => match appstate.db_normalized.get(&appstate.normalizer.normalize(&s)) {
+                Some(item) => (Some(item.clone()), Some(ExactHit::Normalized)),
+                None => (None, None),
+            },
+            None => (None, None),
+        };
+        if string_res.is_none() {
+            appstate.metrics.zero_result_queries.with_label_values(&["/exact_match"]).inc();
+        }
+        result.push(string_res);
+        hits.push(hit);
     }
 
-    return (StatusCode::OK, Json(ExactMatchResponse { matches: result }));
+    return Ok(Json(ExactMatchResponse { matches: result, hits }));
 }
@@ -1,34 +1,61 @@
-use std::str::FromStr;
+use std::{str::FromStr, sync::Arc};
 
-use axum::{Json, http::StatusCode, extract::State};
+use axum::{Extension, Json, http::HeaderMap, extract::State};
+use futures_delay_queue::DelayHandle;
 //use axum_macros::debug_handler;
 use serde::{Deserialize, Serialize};
 use time::Duration;
-use tower_sessions::{Expiry, Session};
+use tower_sessions::{session::Id, Expiry, Session, SessionStore};
 use uuid::Uuid;
 
-use crate::{io::EngineInputData, AppState};
+use crate::{cache, engine::{get_engine, merge_expansions, remove_engine, to_query, to_query_authorship, EngineWrapper}, io::EngineInputData, names::{self, Authorship}, rate_limit::ClientId, AppState};
+
+use super::{error::{ApiError, ApiJson}, limits};
 
 
 // the input request
 #[derive(Debug, Deserialize)]
 pub struct FuzzyAutocompleteRequest {
     string: String,
+    /*
+        Cookie-free mode: the token returned by a previous response, can also be passed in the
+        SESSION_TOKEN_HEADER header. Sending it empty starts a new token session.
+        An unknown or expired token is refused with session_expired, the client then starts a new one.
+        When absent (and no header), the session is tracked with the cookie.
+     */
+    #[serde(default)]
+    session_token: Option<String>,
+    /*
+        Identifies the input when a form has several autocomplete fields, so each one gets its own engine
+        and they don't clobber each other's previous search.
+     */
+    #[serde(default)]
+    field_id: Option<String>,
 }
 
 // the output response
 #[derive(Serialize)]
 pub struct FuzzyAutocompleteResponse {
-    matches: Vec<EngineInputData>
+    matches: Vec<EngineInputData>,
+    // the genera an abbreviated genus ("E. coli") was expanded to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    abbreviation: Option<Vec<String>>,
+    // only in cookie-free mode, to be sent back with the next request
+    #[serde(skip_serializing_if = "Option::is_none")]
+    session_token: Option<String>,
 }
 
+pub const SESSION_TOKEN_HEADER: &str = "x-session-token";
+
 //#[debug_handler]
 pub async fn fuzzy_autocomplete(
-    session: Session,
+    cookie_session: Session,
     State(appstate): State<AppState>, 
-    Json(payload): Json<FuzzyAutocompleteRequest>,
+    Extension(client): Extension<ClientId>,
+    headers: HeaderMap,
+    ApiJson(payload): ApiJson<FuzzyAutocompleteRequest>,
     )
--> (StatusCode, Json<FuzzyAutocompleteResponse>) {
+-> Result<Json<FuzzyAutocompleteResponse>, ApiError> {
     //println!("{:?}", payload);
     let input = payload.string;
     /*println!("Received input: {:?}", input);
@@ -41,105 +68,312 @@ pub async fn fuzzy_autocomplete(
     println!("session id {:?}", session.id());*/
     //session.insert("key", SessionStuff("some stuff".to_owned())).await.unwrap();
 
-    if input.is_empty() {
-        return (StatusCode::BAD_REQUEST, Json(FuzzyAutocompleteResponse{ matches: vec![] }));
+    // blank queries are empty once canonicalized
+    if input.trim().is_empty() {
+        return Err(ApiError::empty_query());
     }
+    limits::check_string(&appstate, &input)?;
 
     {   // the block is necessary because we aquire a lock that needs to go out of scope to be released
-        println!("-- fuzzy request handler      EnginePool {:?} used_engines {:?}", appstate.autocomplete_engine_pool.status(), 
-        appstate.autocomplete_used_engines.lock().await.len());
+        let used_engines = appstate.autocomplete_used_engines.lock().await.len();
+        tracing::debug!(
+            autocomplete_pool = ?appstate.autocomplete_engine_pool.status(),
+            used_engines,
+            stale_engines = appstate.metrics.autocomplete_stale_engines.get(),
+            "fuzzy autocomplete handler");
     }
 
-    match session.id() {
-        Some(sid) => {
-            // follow up requests, session already created, need to reuse it
-            println!("Follow up req, use sid {:?}", sid);
+    let (queries, abbreviation) = autocomplete_queries(&appstate, input);
+
+    if appstate.server_config.stateless_autocomplete {
+        let matches = autocomplete_stateless_expanded(&appstate, queries).await?;
+        count_zero_result(&appstate, &matches);
+        return Ok(Json(FuzzyAutocompleteResponse{ matches, abbreviation, session_token: None }));
+    }
+
+    let session_token = payload.session_token.or_else(|| headers.get(SESSION_TOKEN_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_owned()));
+
+    let Some(session_token) = session_token else {
+        // cookie mode, the session layer takes care of saving the session
+        let matches = autocomplete_in_session(&appstate, &client, &cookie_session, payload.field_id, queries).await?;
+        count_zero_result(&appstate, &matches);
+        return Ok(Json(FuzzyAutocompleteResponse{ matches, abbreviation, session_token: None }));
+    };
 
-            let local_uuid_string: String = session.get(crate::SESSION_ENGINE_KEY).await.unwrap().unwrap();
-            // keep session alive by resetting expiry
-            let local_uuid = Uuid::from_str(&local_uuid_string).unwrap();
-            let mut used_engines = appstate.autocomplete_used_engines.lock().await;/* {
-                Ok(used_engines_map) => used_engines_map,
-                Err(e) => {
-                    println!("Used engine lock error: {}", e.to_string());
-                    return (StatusCode::INTERNAL_SERVER_ERROR, Json(FuzzyMatchResponse{ matches: vec![] }));
-                } 
-            };*/
+    /*
+        Cookie-free mode: the token is the id of a session living in the same store as cookie sessions,
+        so it gets the same expiry. Unlike a missing cookie, an unknown or expired token is an error:
+        the client would otherwise not notice that its fields lost their engines.
+     */
+    let session_id = match session_token.is_empty() {
+        true => None,
+        false => Some(token_session_id(&appstate, &session_token).await?),
+    };
+    let token_session = Session::new(
+        session_id, 
+        Arc::new(appstate.session_store.clone()), 
+        Some(Expiry::OnInactivity(Duration::seconds(appstate.server_config.session_expiry_delay as i64))));
 
-            // we need to get ownership of delay_handle, hence the remove()
-            let (mut session_engine, delay_handle) = used_engines.remove(&local_uuid).unwrap();
-            let result = session_engine.fuzzy_match(input);
+    let matches = autocomplete_in_session(&appstate, &client, &token_session, payload.field_id, queries).await?;
+    count_zero_result(&appstate, &matches);
 
-            //let stuff = delay_handle.borrow_mut();
-            session.set_expiry(Some(Expiry::OnInactivity(Duration::seconds(appstate.server_config.session_expiry_delay as i64))));
-            let new_handle = delay_handle.reset(
-                std::time::Duration::from_secs(appstate.server_config.get_engine_expiry()))
-                .await.unwrap();
-            used_engines.insert(local_uuid, (session_engine, new_handle));
+    // not managed by the session layer, we need to save it ourselves
+    token_session.save().await.map_err(ApiError::session_error)?;
 
+    let session_token = token_session.id().map(|id| id.to_string());
+    return Ok(Json(FuzzyAutocompleteResponse{ matches, abbreviation, session_token }));
+}
 
+/**
+ * The queries to run for input: the expansions of its abbreviated genus if it has one, along with their genera
+ * (see BatchMatcher::expand_abbreviation), otherwise input alone.
+ */
+pub fn autocomplete_queries(appstate: &AppState, input: String) -> (Vec<String>, Option<Vec<String>>) {
+    return match appstate.batch_matcher.expand_abbreviation(&input) {
+        Some((genera, queries)) => (queries, Some(genera)),
+        None => (vec![input], None),
+    };
+}
 
-            return (StatusCode::OK, Json(FuzzyAutocompleteResponse{ matches: result }));
+// the id of a session still in the store
+async fn token_session_id(appstate: &AppState, session_token: &str) -> Result<Id, ApiError> {
+    let Ok(id) = Id::from_str(session_token) else {
+        return Err(ApiError::session_expired());
+    };
+    let record = appstate.session_store.load(&id).await
+        .map_err(|e| ApiError::session_error(e.into()))?;
+    return match record {
+        Some(_) => Ok(id),
+        None => Err(ApiError::session_expired()),
+    };
+}
 
+fn count_zero_result(appstate: &AppState, matches: &[EngineInputData]) {
+    if matches.is_empty() {
+        appstate.metrics.zero_result_queries.with_label_values(&["/fuzzy"]).inc();
+    }
+}
+
+/**
+ * Runs the queries on the engine attributed to the session, attributing one first if needed.
+ */
+async fn autocomplete_in_session(appstate: &AppState, client: &ClientId, session: &Session, field_id: Option<String>, queries: Vec<String>) -> Result<Vec<EngineInputData>, ApiError> {
+    // the default field keeps the original key, so requests without field_id behave as before
+    let engine_key = match &field_id {
+        Some(field_id) => format!("{}:{}", crate::SESSION_ENGINE_KEY, field_id),
+        None => crate::SESSION_ENGINE_KEY.to_owned(),
+    };
+
+    // uuid of the engine previously attributed to this session field, if any
+    let session_uuid = match session.id() {
+        Some(sid) => {
+            // follow up requests, session already created, need to reuse it
+            tracing::Span::current().record("session_id", sid.to_string());
+            tracing::debug!("Follow up req");
+            session_engine_uuid(session, &engine_key).await
         },
         None => {
             // first request, session not fully created yet
-            println!("First req");
-            let mut session_engine = appstate.autocomplete_engine_pool.remove().await.unwrap();/* {
-                Ok(engine) => {
-                    engine
-                },
-                Err(e) => {
-                    // no more engine in pool, or other error
-                    println!("EnginePool error: {}", e.to_string());
-                    return (StatusCode::INTERNAL_SERVER_ERROR, Json(FuzzyMatchResponse{ matches: vec![] }));
-                } 
-            };*/
-
-            // local id
-            let uuid = Uuid::new_v4();
-
-            // attribute this engine to the session
-            
-            /* {
-                Ok(used_engines_map) => used_engines_map,
-                Err(e) => {
-                    println!("Used engine lock error: {}", e.to_string());
-                    return (StatusCode::INTERNAL_SERVER_ERROR, Json(FuzzyMatchResponse{ matches: vec![] }));
-                } 
-            };*/
-
-            
-            //std::mem::drop(used_engines);
-            
-
-            let result = session_engine.fuzzy_match(input);
-
-            session.insert(crate::SESSION_ENGINE_KEY, uuid.to_string()).await.unwrap();
-            let delay_handle = appstate.autocomplete_delay_q.lock().unwrap().insert(
-                uuid, 
-                std::time::Duration::from_secs(appstate.server_config.get_engine_expiry()));
-
-            let mut used_engines = appstate.autocomplete_used_engines.lock().await;
-            used_engines.insert(uuid, (session_engine, delay_handle));
-
-            
-
-            return (StatusCode::OK, Json(FuzzyAutocompleteResponse{ matches: result }));
+            tracing::debug!("First req");
+            None
+        },
+    };
+
+    if session_uuid.is_none() {
+        let accepted = register_session_field(appstate, session, &engine_key).await.map_err(ApiError::session_error)?;
+        if !accepted {
+            tracing::info!(field_id = ?field_id, "Too many fields for session, refuse field");
+            return Err(ApiError::too_many_fields(appstate.server_config.max_fields_per_session));
+        }
+    }
 
+    // no more engine in pool, client holding too many, or other error
+    let (uuid, mut session_engine, delay_handle) = match reserve_engine(appstate, client, session_uuid).await {
+        Ok(reserved) => reserved,
+        Err(e) => {
+            // the field got no engine, it must not use up one of the session's fields
+            if session_uuid.is_none() {
+                unregister_session_field(session, &engine_key).await.map_err(ApiError::session_error)?;
+            }
+            return Err(e);
         },
     };
+    tracing::Span::current().record("engine_uuid", uuid.to_string());
 
-    
+    let result = session_engine.fuzzy_match_expanded(queries, appstate.server_config.autocomplete_result_limit);
 
-    // TODO better manage potential pool error
-   
-    /*for r in result {
-        println!("{:?} {:?}", r.data, r.matcher_columns);
-    }*/
+    // keep session alive by resetting expiry
+    session.set_expiry(Some(Expiry::OnInactivity(Duration::seconds(appstate.server_config.session_expiry_delay as i64))));
+    let uuid = release_engine(appstate, uuid, session_engine, delay_handle).await;
 
-    //self.prev_search_str = input.to_owned();
-    //return Ok(result);
+    if session_uuid != Some(uuid) {
+        // attribute this engine to the session
+        // on error, the engine stays in the used map and will be put back in the pool by its timer
+        session.insert(&engine_key, uuid.to_string()).await.map_err(ApiError::session_error)?;
+    }
 
-    
+    return Ok(result);
+}
+
+/**
+ * Runs each query with autocomplete_stateless, and merges their results (see merge_expansions).
+ */
+async fn autocomplete_stateless_expanded(appstate: &AppState, queries: Vec<String>) -> Result<Vec<EngineInputData>, ApiError> {
+    let mut results = Vec::with_capacity(queries.len());
+    for query in queries {
+        results.push((query.clone(), autocomplete_stateless(appstate, query).await?));
+    }
+    return Ok(merge_expansions(
+        results,
+        appstate.server_config.is_match_on_canonical(),
+        appstate.server_config.autocomplete_result_limit));
+}
+
+/**
+ * Runs the query without any session, from the prefix cache if possible, otherwise on a general purpose engine.
+ */
+async fn autocomplete_stateless(appstate: &AppState, input: String) -> Result<Vec<EngineInputData>, ApiError> {
+    let canonical = appstate.server_config.is_match_on_canonical();
+    // the cache is keyed by query, without authorship, results are tie-broken after
+    let query_authorship = to_query_authorship(&input, canonical);
+    let query = to_query(input.clone(), canonical);
+
+    let cached = appstate.prefix_cache.lock().lookup(&query);
+    if let Some((prefix, candidates)) = cached {
+        appstate.metrics.prefix_cache_hits.inc();
+        if prefix == query {
+            return Ok(first_candidates(&candidates, &query_authorship, appstate.server_config.autocomplete_result_limit));
+        }
+
+        let narrowed = cache::narrow(&candidates, &query, canonical);
+        let result = first_candidates(&narrowed, &query_authorship, appstate.server_config.autocomplete_result_limit);
+        appstate.prefix_cache.lock().insert(query, Arc::new(narrowed));
+        return Ok(result);
+    }
+    appstate.metrics.prefix_cache_misses.inc();
+
+    // given the input, the engine computes the same query, and tie-breaks results itself
+    let mut engine = get_engine(&appstate.gp_engine_pool, appstate.server_config.engine_wait_timeout).await?;
+    let (result, candidates) = engine.fuzzy_match_candidates(
+        input, 
+        appstate.server_config.autocomplete_result_limit, 
+        appstate.server_config.prefix_cache_max_candidates);
+    if let Some(candidates) = candidates {
+        appstate.prefix_cache.lock().insert(query, Arc::new(candidates));
+    }
+
+    return Ok(result);
+}
+
+// the limit first candidates, homonyms tie-broken by the authorship of the query
+fn first_candidates(candidates: &[EngineInputData], query_authorship: &Authorship, limit: usize) -> Vec<EngineInputData> {
+    let ranked = candidates.iter().map(|candidate| (names::canonical(&candidate.string), candidate));
+    return names::tie_break(query_authorship, ranked, limit);
+}
+
+/**
+ * Adds the field to the ones known by the session, unless the session already has too many.
+ * Returns false in that case. A field already known is always accepted.
+ */
+async fn register_session_field(appstate: &AppState, session: &Session, engine_key: &str) -> Result<bool, tower_sessions::session::Error> {
+    let mut fields: Vec<String> = session.get(crate::SESSION_FIELDS_KEY).await?.unwrap_or_default();
+    if fields.iter().any(|field| field == engine_key) {
+        return Ok(true);
+    }
+    if fields.len() >= appstate.server_config.max_fields_per_session {
+        return Ok(false);
+    }
+
+    fields.push(engine_key.to_owned());
+    session.insert(crate::SESSION_FIELDS_KEY, fields).await?;
+    return Ok(true);
+}
+
+/**
+ * Removes a field registered by register_session_field, when no engine could be attributed to it.
+ */
+async fn unregister_session_field(session: &Session, engine_key: &str) -> Result<(), tower_sessions::session::Error> {
+    let mut fields: Vec<String> = session.get(crate::SESSION_FIELDS_KEY).await?.unwrap_or_default();
+    fields.retain(|field| field != engine_key);
+    session.insert(crate::SESSION_FIELDS_KEY, fields).await?;
+    return Ok(());
+}
+
+/**
+ * Reads the engine uuid stored in the session under engine_key.
+ * Anything missing or unreadable is treated as if the session had no engine.
+ */
+async fn session_engine_uuid(session: &Session, engine_key: &str) -> Option<Uuid> {
+    let uuid_string: String = match session.get(engine_key).await {
+        Ok(Some(uuid_string)) => uuid_string,
+        Ok(None) => return None,
+        Err(e) => {
+            tracing::warn!(error = %e, "Session error, ignore engine");
+            return None;
+        },
+    };
+
+    return Uuid::from_str(&uuid_string).ok();
+}
+
+/**
+ * Takes the engine identified by prev_uuid out of the used engine map.
+ * If there is none (first request, or the engine was already reclaimed by its timer because
+ * the session outlived it), a fresh engine is taken from the pool under a new uuid.
+ * The delay handle is None for a fresh engine, as no timer exists for it yet.
+ * A fresh engine is refused when the client already holds max_engines_per_client engines.
+ */
+pub async fn reserve_engine(appstate: &AppState, client: &ClientId, prev_uuid: Option<Uuid>)
+-> Result<(Uuid, EngineWrapper, Option<DelayHandle>), ApiError> {
+    if let Some(uuid) = prev_uuid {
+        // we need to get ownership of delay_handle, hence the remove()
+        if let Some((engine, delay_handle)) = appstate.autocomplete_used_engines.lock().await.remove(&uuid) {
+            return Ok((uuid, engine, Some(delay_handle)));
+        }
+
+        tracing::warn!(engine_uuid = %uuid, "Engine not found, acquire a new one");
+        appstate.metrics.autocomplete_stale_engines.inc();
+    }
+
+    let uuid = Uuid::new_v4();
+    if !appstate.client_engines.try_hold(client, uuid) {
+        tracing::info!(client = ?client, "Too many engines for client, refuse");
+        appstate.metrics.client_rejections.with_label_values(&["too_many_engines"]).inc();
+        return Err(ApiError::too_many_engines(appstate.client_engines.max_per_client()));
+    }
+    let engine = match remove_engine(&appstate.autocomplete_engine_pool, appstate.server_config.engine_wait_timeout).await {
+        Ok(engine) => engine,
+        Err(e) => {
+            appstate.client_engines.release(&uuid);
+            return Err(e.into());
+        },
+    };
+    return Ok((uuid, engine, None));
+}
+
+/**
+ * Puts a reserved engine back in the used engine map, and (re)starts its timer.
+ * Returns the uuid under which the engine is now stored. It differs from the given one
+ * if the timer had already fired in the meantime, as the cleanup handler has consumed the old uuid.
+ */
+pub async fn release_engine(appstate: &AppState, uuid: Uuid, engine: EngineWrapper, delay_handle: Option<DelayHandle>) -> Uuid {
+    let engine_expiry = std::time::Duration::from_secs(appstate.server_config.get_engine_expiry());
+
+    let (uuid, delay_handle) = match delay_handle {
+        Some(delay_handle) => match delay_handle.reset(engine_expiry).await {
+            Ok(new_handle) => (uuid, new_handle),
+            Err(_) => {
+                tracing::debug!(engine_uuid = %uuid, "Timer already expired for engine, use a new id");
+                let new_uuid = Uuid::new_v4();
+                appstate.client_engines.rename(&uuid, new_uuid);
+                (new_uuid, appstate.autocomplete_delay_q.lock().insert(new_uuid, engine_expiry))
+            },
+        },
+        None => (uuid, appstate.autocomplete_delay_q.lock().insert(uuid, engine_expiry)),
+    };
+
+    appstate.autocomplete_used_engines.lock().await.insert(uuid, (engine, delay_handle));
+    return uuid;
 }
@@ -1,19 +1,45 @@
-use axum::{Json, http::StatusCode, extract::State};
+use std::sync::Arc;
+
+use axum::{Extension, Json, extract::State, http::StatusCode};
+use deadpool::unmanaged::PoolError;
 //use axum_macros::debug_handler;
 use serde::{Deserialize, Serialize};
-use tower_sessions::Session;
 
-use crate::{io::EngineInputData, AppState};
+use tokio::sync::OwnedSemaphorePermit;
+
+use crate::{auth::ApiKey, batch::BatchMatcher, io::EngineInputData, AppState};
+
+use super::{error::{ApiError, ApiJson}, limits};
 
 
 
 #[derive(Debug, Deserialize)]
 pub struct FuzzyMatchRequest {
     strings: Vec<String>,
+    #[serde(flatten)]
+    options: FuzzyMatchOptions,
+}
+
+// shared with /fuzzy_match/stream, where they are given in the query string
+#[derive(Debug, Deserialize)]
+pub struct FuzzyMatchOptions {
     #[serde(default = "default_to_one")]
     n_first_results: u32,
 }
 
+impl FuzzyMatchOptions {
+    // number of results per string, refused if over fuzzy_match_result_limit
+    pub fn result_limit(&self, appstate: &AppState) -> Result<usize, ApiError> {
+        if self.n_first_results as usize > appstate.server_config.fuzzy_match_result_limit {
+            return Err(ApiError::too_many_results(self.n_first_results, appstate.server_config.fuzzy_match_result_limit));
+        }
+        return Ok(self.n_first_results as usize);
+    }
+}
+
+// batches smaller than this are not split, the overhead would not be worth it
+const MIN_CHUNK_SIZE: usize = 100;
+
 fn default_to_one() -> u32 {
     return 1;
 }
@@ -24,35 +50,135 @@ fn default_to_one() -> u32 {
  */
 #[derive(Serialize)]
 pub struct FuzzyMatchResponse {
-    matches: Vec<Vec<EngineInputData>>
+    matches: Vec<Vec<EngineInputData>>,
+    // same order as matches, the genera an abbreviated genus ("E. coli") was expanded to, null when it was not
+    abbreviations: Vec<Option<Vec<String>>>,
+}
+
+/**
+ * The matches of one string. abbreviation is set when the string had an abbreviated genus,
+ * to the genera it was expanded to (see BatchMatcher::fuzzy_match_expanded).
+ * Also a line of the results of a job.
+ */
+#[derive(Serialize, Deserialize)]
+pub struct StringMatches {
+    pub matches: Vec<EngineInputData>,
+    pub abbreviation: Option<Vec<String>>,
 }
 
 pub async fn fuzzy_match(
     State(appstate): State<AppState>, 
-    Json(payload): Json<FuzzyMatchRequest>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    ApiJson(payload): ApiJson<FuzzyMatchRequest>,
     )
--> (StatusCode, Json<FuzzyMatchResponse>) {
+-> Result<Json<FuzzyMatchResponse>, ApiError> {
 
     let input_vec = payload.strings;
-    let limit = payload.n_first_results as usize;
     if input_vec.is_empty() {
-        return (StatusCode::BAD_REQUEST, Json(FuzzyMatchResponse{ matches: vec![] }));
+        return Err(ApiError::empty_query());
     }
+    let limit = payload.options.result_limit(&appstate)?;
+    limits::check_batch(&appstate, &api_key, &input_vec)?;
+
+    tracing::debug!(available_workers = appstate.batch_workers.available_permits(), "fuzzy_match handler");
+
+    let matching_timer = appstate.metrics.fuzzy_match_duration.start_timer();
+    let result = match_in_parallel(&appstate, input_vec, "/fuzzy_match", move |batch_matcher, chunk| {
+        return match_chunk(batch_matcher, chunk, limit);
+    }).await?;
+    matching_timer.observe_duration();
+
+    let (matches, abbreviations) = result.into_iter()
+        .map(|string_matches| (string_matches.matches, string_matches.abbreviation))
+        .unzip();
+    return Ok(Json(FuzzyMatchResponse { matches, abbreviations }));
+}
 
-    println!("-- fuzzy request handler      EnginePool {:?}", appstate.gp_engine_pool.status());
-    
-    let mut engine = appstate.gp_engine_pool.get().await.unwrap();
-    let mut result: Vec<Vec<EngineInputData>> = Vec::new();
-    for s in input_vec {
+/**
+ * Runs match_chunk on contiguous chunks of strings, in parallel with batch workers, and returns the results in input order.
+ * Only the first worker is waited for, the others are taken if they are free right now,
+ * so a busy server degrades to fewer chunks instead of queueing.
+ * match_chunk also returns its number of zero result queries, counted for route.
+ * Without strings, no worker is waited for: /match and /reconcile/csv call it with none when everything matched exactly.
+ */
+pub async fn match_in_parallel<T, F>(appstate: &AppState, strings: Vec<String>, route: &str, match_chunk: F) -> Result<Vec<T>, ApiError>
+where
+    T: Send + 'static,
+    F: Fn(&BatchMatcher, Vec<String>) -> (Vec<T>, u64) + Clone + Send + 'static,
+{
+    if strings.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let wanted_workers = std::cmp::min(
+        appstate.server_config.fuzzy_match_parallelism,
+        strings.len().div_ceil(MIN_CHUNK_SIZE));
+    let mut workers = vec![acquire_worker(appstate).await?];
+    while workers.len() < wanted_workers {
+        match appstate.batch_workers.clone().try_acquire_owned() {
+            Ok(worker) => workers.push(worker),
+            Err(_) => break,
+        }
+    }
+    tracing::debug!(workers = workers.len(), strings = strings.len(), "Batch chunks");
+
+    let batch_size = strings.len();
+    let chunk_size = batch_size.div_ceil(workers.len());
+    let mut strings = strings.into_iter();
+    let mut tasks = Vec::with_capacity(workers.len());
+    for worker in workers {
+        let chunk = strings.by_ref().take(chunk_size).collect::<Vec<String>>();
+        let batch_matcher = appstate.batch_matcher.clone();
+        let match_chunk = match_chunk.clone();
+        tasks.push(tokio::task::spawn_blocking(move || {
+            let result = match_chunk(&batch_matcher, chunk);
+            drop(worker);
+            return result;
+        }));
+    }
+
+    // chunks are awaited in order, so results stay in the order of the input
+    let mut result: Vec<T> = Vec::with_capacity(batch_size);
+    for task in tasks {
+        let (chunk_result, zero_results) = task.await.map_err(|e| {
+            tracing::error!(error = %e, "Match task error");
+            return ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "match_failed", "Matching the batch failed");
+        })?;
+        appstate.metrics.zero_result_queries.with_label_values(&[route]).inc_by(zero_results);
+        result.extend(chunk_result);
+    }
+    return Ok(result);
+}
+
+// like get_engine for the gp pool, waits at most engine_wait_timeout seconds for a batch worker
+pub async fn acquire_worker(appstate: &AppState) -> Result<OwnedSemaphorePermit, ApiError> {
+    let timeout = std::time::Duration::from_secs(appstate.server_config.engine_wait_timeout);
+    return match tokio::time::timeout(timeout, appstate.batch_workers.clone().acquire_owned()).await {
+        Ok(Ok(worker)) => Ok(worker),
+        Ok(Err(_)) => Err(PoolError::Closed.into()),
+        Err(_) => Err(PoolError::Timeout.into()),
+    };
+}
+
+/**
+ * Matches every string of the chunk, expanding abbreviated genera.
+ * Empty strings get no result, and are not counted as zero result queries.
+ */
+pub fn match_chunk(batch_matcher: &BatchMatcher, chunk: Vec<String>, limit: usize) -> (Vec<StringMatches>, u64) {
+    let mut matcher = BatchMatcher::new_matcher();
+    let mut zero_results = 0;
+    let mut result = Vec::with_capacity(chunk.len());
+    for s in chunk {
         if s.is_empty() {
-            result.push(vec![]);
+            result.push(StringMatches { matches: vec![], abbreviation: None });
             continue;
         }
 
-        let string_res = engine.fuzzy_match(s); //[0..payload.n_first_results as usize].to_owned();
-        let result_count_limit = std::cmp::min(string_res.len(), limit);
-        result.push(string_res[0..result_count_limit].to_owned());
+        let (matches, abbreviation) = batch_matcher.fuzzy_match_expanded(&mut matcher, s, limit);
+        if matches.is_empty() {
+            zero_results += 1;
+        }
+        result.push(StringMatches { matches, abbreviation });
     }
-
-    return (StatusCode::OK, Json(FuzzyMatchResponse { matches: result }));
+    return (result, zero_results);
 }
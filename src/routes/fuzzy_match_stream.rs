@@ -0,0 +1,259 @@
+use std::convert::Infallible;
+
+use axum::{body::{Body, BodyDataStream, Bytes}, extract::State, http::header, response::{IntoResponse, Response}};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tracing::Instrument;
+
+use crate::{io::EngineInputData, AppState};
+
+use super::{error::{ApiError, ApiQuery}, fuzzy_match::{match_chunk, FuzzyMatchOptions}, limits};
+
+
+// lines matched together, a chunk is also what is sent back at once
+const STREAM_CHUNK_SIZE: usize = 100;
+// chunks of results waiting for the client to read them, before we stop reading its queries
+const STREAM_BUFFERED_CHUNKS: usize = 4;
+
+// an input line: either a json string, or an object with a string field
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StreamQuery {
+    Plain(String),
+    Object { string: String },
+}
+
+#[derive(Serialize)]
+struct LineError {
+    code: &'static str,
+    detail: String,
+}
+
+// last output line when the stream ends before the whole body was answered, see error_line
+#[derive(Serialize)]
+struct StreamError {
+    error: LineError,
+}
+
+// an output line, line is the number of the input line it answers, starting at 1
+#[derive(Serialize)]
+struct StreamResult {
+    line: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    matches: Option<Vec<EngineInputData>>,
+    // the genera an abbreviated genus ("E. coli") was expanded to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    abbreviation: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<LineError>,
+}
+
+/**
+ * NDJSON in, NDJSON out: each input line is a query, answered by a StreamResult line as soon as its chunk is matched.
+ * Options are the ones of /fuzzy_match, in the query string. Blank lines are skipped, but still counted.
+ * A line that can't be read gets an error result, the others are still answered.
+ * A line longer than max_body_size gets a line_too_long error result and is skipped, up to its newline.
+ * If matching fails, or the body can't be read to its end, the response ends with a line holding only an error,
+ * without line number: internal_error or body_error. Lines after the last complete one are then not answered.
+ *
+ * Memory stays bounded: queries are only read as long as the client reads the results,
+ * and no input line can exceed max_body_size. There is no limit on the number of lines.
+ */
+pub async fn fuzzy_match_stream(
+    State(appstate): State<AppState>,
+    ApiQuery(options): ApiQuery<FuzzyMatchOptions>,
+    body: Body,
+    )
+-> Result<Response, ApiError> {
+    let limit = options.result_limit(&appstate)?;
+
+    let (tx, rx) = mpsc::channel::<Bytes>(STREAM_BUFFERED_CHUNKS);
+    tokio::spawn(stream_matches(appstate, body.into_data_stream(), tx, limit).instrument(tracing::Span::current()));
+
+    let results = futures_util::stream::unfold(rx, |mut rx| async move {
+        return rx.recv().await.map(|chunk| (Ok::<Bytes, Infallible>(chunk), rx));
+    });
+    return Ok(([(header::CONTENT_TYPE, "application/x-ndjson")], Body::from_stream(results)).into_response());
+}
+
+/**
+ * Reads the body line by line, and sends the results of each chunk of lines to tx.
+ * Stops when the body ends, can't be read, or when the client went away.
+ */
+async fn stream_matches(appstate: AppState, mut body: BodyDataStream, tx: mpsc::Sender<Bytes>, limit: usize) {
+    let max_line_size = appstate.server_config.max_body_size;
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut line_number = 0;
+    let mut chunk: Vec<(usize, Result<String, LineError>)> = Vec::with_capacity(STREAM_CHUNK_SIZE);
+    // in a line too long, already answered with an error
+    let mut skipping_line = false;
+
+    loop {
+        let body_ended = match body.next().await {
+            Some(Ok(data)) => {
+                buffer.extend_from_slice(&data);
+                false
+            },
+            Some(Err(e)) => {
+                // the last line is incomplete, the ones before it are still answered
+                tracing::warn!(error = %e, "Stream body error, stop reading");
+                if !chunk.is_empty() && !send_matches(&appstate, &tx, std::mem::take(&mut chunk), limit).await {
+                    return;
+                }
+                let _ = tx.send(error_line("body_error", format!("The body could not be read past line {}: {}", line_number, e))).await;
+                return;
+            },
+            None => true,
+        };
+
+        // the rest of a line too long is dropped as it comes, until its newline
+        if skipping_line {
+            match buffer.iter().position(|byte| *byte == b'\n') {
+                Some(end) => {
+                    buffer.drain(..=end);
+                    skipping_line = false;
+                },
+                None => buffer.clear(),
+            }
+        }
+
+        // every complete line, and the last one if the body ended without a final newline
+        let mut start = 0;
+        while let Some(end) = buffer[start..].iter().position(|byte| *byte == b'\n').map(|position| start + position) {
+            line_number += 1;
+            if let Some(query) = parse_line(&appstate, &buffer[start..end]) {
+                chunk.push((line_number, query));
+            }
+            start = end + 1;
+        }
+        buffer.drain(..start);
+        if body_ended && !buffer.is_empty() {
+            line_number += 1;
+            if let Some(query) = parse_line(&appstate, &buffer) {
+                chunk.push((line_number, query));
+            }
+            buffer.clear();
+        }
+
+        if buffer.len() > max_line_size {
+            line_number += 1;
+            chunk.push((line_number, Err(LineError {
+                code: "line_too_long",
+                detail: format!("The line is longer than the maximum of {} bytes", max_line_size),
+            })));
+            buffer.clear();
+            skipping_line = true;
+        }
+
+        if chunk.len() >= STREAM_CHUNK_SIZE || (body_ended && !chunk.is_empty()) {
+            let lines = std::mem::replace(&mut chunk, Vec::with_capacity(STREAM_CHUNK_SIZE));
+            if !send_matches(&appstate, &tx, lines, limit).await {
+                return;
+            }
+        }
+
+        if body_ended {
+            return;
+        }
+    }
+}
+
+/**
+ * Matches lines and sends their results to tx. Returns false when the stream must stop:
+ * the client went away, or matching failed, which is then told to the client with an internal_error line.
+ */
+async fn send_matches(appstate: &AppState, tx: &mpsc::Sender<Bytes>, lines: Vec<(usize, Result<String, LineError>)>, limit: usize) -> bool {
+    let results = match match_lines(appstate, lines, limit).await {
+        Some(results) => results,
+        None => {
+            // so the client can tell this from a complete response
+            let _ = tx.send(error_line("internal_error", "Matching failed, the stream stops here".to_owned())).await;
+            return false;
+        },
+    };
+    // waits here while the client is not reading, so we stop reading its queries too
+    if tx.send(results).await.is_err() {
+        tracing::debug!("Stream client went away");
+        return false;
+    }
+    return true;
+}
+
+// the last line of a stream that ends before the whole body was answered
+fn error_line(code: &'static str, detail: String) -> Bytes {
+    let error = StreamError { error: LineError { code, detail } };
+    let mut encoded = serde_json::to_vec(&error).expect("error is always serializable");
+    encoded.push(b'\n');
+    return Bytes::from(encoded);
+}
+
+// None for blank lines
+fn parse_line(appstate: &AppState, line: &[u8]) -> Option<Result<String, LineError>> {
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    if line.iter().all(|byte| byte.is_ascii_whitespace()) {
+        return None;
+    }
+
+    let string = match serde_json::from_slice::<StreamQuery>(line) {
+        Ok(StreamQuery::Plain(string)) | Ok(StreamQuery::Object { string }) => string,
+        Err(e) => return Some(Err(LineError { code: "invalid_line", detail: e.to_string() })),
+    };
+    if limits::check_string(appstate, &string).is_err() {
+        return Some(Err(LineError {
+            code: "string_too_long",
+            detail: format!("The string is longer than the maximum of {} characters", appstate.server_config.max_string_length),
+        }));
+    }
+    return Some(Ok(string));
+}
+
+/**
+ * Matches the queries of a chunk with a batch worker, and encodes the results as NDJSON, in line order.
+ * None if matching failed.
+ */
+async fn match_lines(appstate: &AppState, lines: Vec<(usize, Result<String, LineError>)>, limit: usize) -> Option<Bytes> {
+    // waits for as long as needed, the client is streaming anyway
+    let worker = appstate.batch_workers.clone().acquire_owned().await.ok()?;
+    let batch_matcher = appstate.batch_matcher.clone();
+
+    let task = tokio::task::spawn_blocking(move || {
+        let strings = lines.iter()
+            .filter_map(|(_, query)| query.as_ref().ok().cloned())
+            .collect::<Vec<String>>();
+        let (matches, zero_results) = match_chunk(&batch_matcher, strings, limit);
+        drop(worker);
+
+        // matches are in the order of the valid lines
+        let mut matches = matches.into_iter();
+        let mut encoded = Vec::new();
+        for (line, query) in lines {
+            let result = match query {
+                Ok(_) => {
+                    let string_matches = matches.next();
+                    StreamResult {
+                        line,
+                        abbreviation: string_matches.as_ref().and_then(|string_matches| string_matches.abbreviation.clone()),
+                        matches: string_matches.map(|string_matches| string_matches.matches),
+                        error: None,
+                    }
+                },
+                Err(error) => StreamResult { line, matches: None, abbreviation: None, error: Some(error) },
+            };
+            serde_json::to_writer(&mut encoded, &result).expect("result is always serializable");
+            encoded.push(b'\n');
+        }
+        return (Bytes::from(encoded), zero_results);
+    });
+
+    return match task.await {
+        Ok((encoded, zero_results)) => {
+            appstate.metrics.zero_result_queries.with_label_values(&["/fuzzy_match/stream"]).inc_by(zero_results);
+            Some(encoded)
+        },
+        Err(e) => {
+            tracing::error!(error = %e, "Match task error");
+            None
+        },
+    };
+}
@@ -0,0 +1,188 @@
+use std::sync::Arc;
+
+use axum::{body::Body, extract::{Path, State}, http::{header, HeaderMap, StatusCode}, response::{IntoResponse, Response}, Extension, Json};
+use serde::{Deserialize, Serialize};
+
+use crate::{auth::ApiKey, csv_table::{self, CsvTable}, io::EngineInputData, jobs::{self, JobInfo, JobStatus}, AppState};
+
+use super::{error::{ApiError, ApiQuery}, fuzzy_match::{FuzzyMatchOptions, StringMatches}, limits};
+
+
+// json body of a new job, csv bodies get the same options in the query string
+#[derive(Debug, Deserialize)]
+pub struct JobRequest {
+    strings: Vec<String>,
+    #[serde(flatten)]
+    options: FuzzyMatchOptions,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CsvParams {
+    // header of the column holding the names, the first column by default
+    column: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResultsFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResultsParams {
+    #[serde(default)]
+    format: ResultsFormat,
+}
+
+// same as the /fuzzy_match response
+#[derive(Serialize)]
+pub struct JobResultsResponse {
+    matches: Vec<Vec<EngineInputData>>,
+    abbreviations: Vec<Option<Vec<String>>>,
+}
+
+/**
+ * Creates a job from a json body like the /fuzzy_match one, or from a CSV/TSV file of names, and starts it.
+ * Answers right away with the job, its progress is then polled at /jobs/{id}.
+ */
+pub async fn create_job(
+    State(appstate): State<AppState>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    ApiQuery(csv_params): ApiQuery<CsvParams>,
+    ApiQuery(query_options): ApiQuery<FuzzyMatchOptions>,
+    headers: HeaderMap,
+    body: Body,
+    )
+-> Result<Response, ApiError> {
+    let max_job_body_size = appstate.server_config.max_job_body_size;
+    let content = axum::body::to_bytes(body, max_job_body_size).await
+        .map_err(|e| ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "body_too_large",
+            format!("The body could not be read whole, it is limited to {} bytes: {}", max_job_body_size, e)))?;
+
+    let (strings, options) = match csv_table::delimiter_from_headers(&headers) {
+        Some(delimiter) => {
+            let table = CsvTable::parse(&content, delimiter).map_err(ApiError::invalid_csv)?;
+            let column = table.column_index(csv_params.column.as_deref()).map_err(ApiError::invalid_csv)?;
+            (table.column_values(column), query_options)
+        },
+        None => {
+            let request = serde_json::from_slice::<JobRequest>(&content)
+                .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, "invalid_body", e.to_string()))?;
+            (request.strings, request.options)
+        },
+    };
+
+    if strings.is_empty() {
+        return Err(ApiError::empty_query());
+    }
+    if strings.len() > appstate.server_config.max_job_strings {
+        return Err(ApiError::batch_too_large(strings.len(), appstate.server_config.max_job_strings));
+    }
+    limits::check_strings(&appstate, &strings)?;
+    let limit = options.result_limit(&appstate)?;
+
+    let owner = api_key.map(|Extension(api_key)| api_key.name.clone());
+    let store = appstate.job_store.clone();
+    let info = tokio::task::spawn_blocking(move || store.create(&strings, limit, owner)).await
+        .map_err(|e| ApiError::job_storage_error(e.to_string()))?
+        .map_err(|e| ApiError::job_storage_error(e.to_string()))?;
+    tracing::info!(job_id = info.id, total = info.total, "Job created");
+
+    tokio::spawn(jobs::run_job(appstate.clone(), info.id.clone()));
+
+    let location = format!("/jobs/{}", info.id);
+    return Ok((StatusCode::ACCEPTED, [(header::LOCATION, location)], Json(info)).into_response());
+}
+
+// status and progress (done out of total strings)
+pub async fn get_job(
+    State(appstate): State<AppState>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    Path(id): Path<String>,
+    )
+-> Result<Json<JobInfo>, ApiError> {
+    return Ok(Json(find_job(&appstate, &api_key, &id)?));
+}
+
+/**
+ * Results of a finished job, as json (same as /fuzzy_match) or as CSV, one row per match.
+ */
+pub async fn job_results(
+    State(appstate): State<AppState>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    Path(id): Path<String>,
+    ApiQuery(params): ApiQuery<ResultsParams>,
+    )
+-> Result<Response, ApiError> {
+    let info = find_job(&appstate, &api_key, &id)?;
+    match info.status {
+        JobStatus::Done => {},
+        JobStatus::Failed => return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "job_failed",
+            format!("The job failed: {}", info.error.unwrap_or_default()))),
+        _ => return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "job_not_finished",
+            format!("The job is not finished, {} out of {} strings done", info.done, info.total))),
+    }
+
+    let store = appstate.job_store.clone();
+    let (strings, results) = tokio::task::spawn_blocking(move || {
+        return Ok::<_, std::io::Error>((store.read_input(&id)?, store.read_results(&id)?));
+    }).await
+        .map_err(|e| ApiError::job_storage_error(e.to_string()))?
+        .map_err(|e| ApiError::job_storage_error(e.to_string()))?;
+
+    return match params.format {
+        ResultsFormat::Json => {
+            let (matches, abbreviations) = results.into_iter()
+                .map(|string_matches| (string_matches.matches, string_matches.abbreviation))
+                .unzip();
+            Ok(Json(JobResultsResponse { matches, abbreviations }).into_response())
+        },
+        ResultsFormat::Csv => {
+            let csv = results_to_csv(&strings, &results).map_err(|e| ApiError::job_storage_error(e.to_string()))?;
+            let disposition = format!("attachment; filename=\"job-{}.csv\"", info.id);
+            Ok(([(header::CONTENT_TYPE, "text/csv".to_owned()), (header::CONTENT_DISPOSITION, disposition)], csv).into_response())
+        },
+    };
+}
+
+// jobs of other API keys are not found, rather than forbidden, so their ids can't be probed
+fn find_job(appstate: &AppState, api_key: &Option<Extension<Arc<ApiKey>>>, id: &str) -> Result<JobInfo, ApiError> {
+    let requester = api_key.as_ref().map(|Extension(api_key)| api_key.name.as_str());
+    return match appstate.job_store.get(id) {
+        Some(info) if info.owner.as_deref() == requester => Ok(info),
+        _ => Err(ApiError::new(StatusCode::NOT_FOUND, "job_not_found", format!("No job {}", id))),
+    };
+}
+
+/*
+    index is the position of the string in the input, rank the one of the match, strings without match get one empty row.
+    abbreviation is the genera an abbreviated genus was expanded to, separated by |, empty when it was not.
+ */
+fn results_to_csv(strings: &[String], results: &[StringMatches]) -> Result<Vec<u8>, csv::Error> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(["index", "query", "rank", "string", "data", "abbreviation"])?;
+    for (index, (string, string_matches)) in strings.iter().zip(results).enumerate() {
+        let abbreviation = string_matches.abbreviation.as_ref().map(|genera| genera.join("|")).unwrap_or_default();
+        if string_matches.matches.is_empty() {
+            writer.write_record([index.to_string().as_str(), string, "", "", "", &abbreviation])?;
+        }
+        for (rank, item) in string_matches.matches.iter().enumerate() {
+            writer.write_record([
+                index.to_string().as_str(),
+                string,
+                (rank + 1).to_string().as_str(),
+                &item.string,
+                &item.data.to_string(),
+                &abbreviation])?;
+        }
+    }
+    return writer.into_inner().map_err(|e| e.into_error().into());
+}
@@ -0,0 +1,41 @@
+use std::sync::Arc;
+
+use axum::Extension;
+
+use crate::{auth::ApiKey, AppState};
+
+use super::error::ApiError;
+
+
+/**
+ * Refuses batches the server should not even start working on: more strings than max_batch_size
+ * (or than the key allows, if lower), or any string longer than max_string_length.
+ */
+pub fn check_batch(appstate: &AppState, api_key: &Option<Extension<Arc<ApiKey>>>, strings: &[String]) -> Result<(), ApiError> {
+    let key_max_batch_size = api_key.as_ref().and_then(|Extension(api_key)| api_key.max_batch_size);
+    let max_batch_size = match key_max_batch_size {
+        Some(key_max_batch_size) => std::cmp::min(key_max_batch_size, appstate.server_config.max_batch_size),
+        None => appstate.server_config.max_batch_size,
+    };
+    if strings.len() > max_batch_size {
+        return Err(ApiError::batch_too_large(strings.len(), max_batch_size));
+    }
+
+    return check_strings(appstate, strings);
+}
+
+// every string must be at most max_string_length long, the error tells which one is not
+pub fn check_strings(appstate: &AppState, strings: &[String]) -> Result<(), ApiError> {
+    for (index, string) in strings.iter().enumerate() {
+        check_string(appstate, string).map_err(|_| ApiError::string_too_long(Some(index), appstate.server_config.max_string_length))?;
+    }
+    return Ok(());
+}
+
+pub fn check_string(appstate: &AppState, string: &str) -> Result<(), ApiError> {
+    // cheap check first, a string can't have more chars than bytes
+    if string.len() > appstate.server_config.max_string_length && string.chars().count() > appstate.server_config.max_string_length {
+        return Err(ApiError::string_too_long(None, appstate.server_config.max_string_length));
+    }
+    return Ok(());
+}
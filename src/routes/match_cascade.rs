@@ -0,0 +1,95 @@
+use std::sync::Arc;
+
+use axum::{extract::State, Extension, Json};
+use serde::{Deserialize, Serialize};
+
+use crate::{auth::ApiKey, io::EngineInputData, AppState};
+
+use super::{error::{ApiError, ApiJson}, fuzzy_match::{match_chunk, match_in_parallel, FuzzyMatchOptions}, limits};
+
+
+// same as the /fuzzy_match request
+#[derive(Debug, Deserialize)]
+pub struct MatchRequest {
+    strings: Vec<String>,
+    #[serde(flatten)]
+    options: FuzzyMatchOptions,
+}
+
+// the stage that matched a string, each one is only tried if the previous ones found nothing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchStage {
+    Exact,
+    Normalized,
+    Fuzzy,
+    None,
+}
+
+#[derive(Serialize)]
+pub struct MatchResult {
+    stage: MatchStage,
+    // the exact match alone, or up to n_first_results fuzzy matches
+    matches: Vec<EngineInputData>,
+    // fuzzy matches only, the genera an abbreviated genus ("E. coli") was expanded to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    abbreviation: Option<Vec<String>>,
+}
+
+#[derive(Serialize)]
+pub struct MatchResponse {
+    results: Vec<MatchResult>,
+}
+
+/**
+ * /exact_match, then exact match on normalized strings, then /fuzzy_match, in a single request.
+ * Results are in the order of the strings, each one telling which stage matched.
+ */
+pub async fn match_cascade(
+    State(appstate): State<AppState>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    ApiJson(payload): ApiJson<MatchRequest>,
+    )
+-> Result<Json<MatchResponse>, ApiError> {
+    let strings = payload.strings;
+    if strings.is_empty() {
+        return Err(ApiError::empty_query());
+    }
+    let limit = payload.options.result_limit(&appstate)?;
+    limits::check_batch(&appstate, &api_key, &strings)?;
+
+    let mut results = strings.iter()
+        .map(|string| {
+            if let Some(item) = appstate.db_hashmap.get(string) {
+                return MatchResult { stage: MatchStage::Exact, matches: vec![item.clone()], abbreviation: None };
+            }
+            if let Some(item) = appstate.db_normalized.get(&appstate.normalizer.normalize(string)) {
+                return MatchResult { stage: MatchStage::Normalized, matches: vec![item.clone()], abbreviation: None };
+            }
+            return MatchResult { stage: MatchStage::None, matches: vec![], abbreviation: None };
+        })
+        .collect::<Vec<MatchResult>>();
+
+    // the rest goes through fuzzy matching, except empty strings
+    let fuzzy_indexes = results.iter().zip(&strings)
+        .enumerate()
+        .filter(|(_, (result, string))| result.stage == MatchStage::None && !string.is_empty())
+        .map(|(index, _)| index)
+        .collect::<Vec<usize>>();
+    let fuzzy_strings = fuzzy_indexes.iter().map(|index| strings[*index].clone()).collect::<Vec<String>>();
+    let fuzzy_matches = match_in_parallel(&appstate, fuzzy_strings, "/match", move |batch_matcher, chunk| {
+        return match_chunk(batch_matcher, chunk, limit);
+    }).await?;
+
+    for (index, string_matches) in fuzzy_indexes.into_iter().zip(fuzzy_matches) {
+        if !string_matches.matches.is_empty() {
+            results[index] = MatchResult {
+                stage: MatchStage::Fuzzy,
+                matches: string_matches.matches,
+                abbreviation: string_matches.abbreviation,
+            };
+        }
+    }
+
+    return Ok(Json(MatchResponse { results }));
+}
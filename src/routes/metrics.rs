@@ -0,0 +1,52 @@
+use std::{sync::atomic::Ordering, time::Instant};
+
+use axum::{extract::{MatchedPath, Request, State}, http::header, middleware::Next, response::{IntoResponse, Response}};
+
+use crate::AppState;
+
+
+/**
+ * Middleware counting requests and timing them, labelled with the route they matched.
+ */
+pub async fn track_requests(
+    State(appstate): State<AppState>,
+    matched_path: Option<MatchedPath>,
+    request: Request,
+    next: Next,
+    )
+-> Response {
+    let route = match &matched_path {
+        Some(matched_path) => matched_path.as_str().to_owned(),
+        None => "unmatched".to_owned(),
+    };
+
+    let start = Instant::now();
+    let response = next.run(request).await;
+
+    appstate.metrics.request_duration.with_label_values(&[&route]).observe(start.elapsed().as_secs_f64());
+    appstate.metrics.requests.with_label_values(&[&route, response.status().as_str()]).inc();
+    return response;
+}
+
+pub async fn metrics(State(appstate): State<AppState>) -> Response {
+    let metrics = &appstate.metrics;
+
+    // gauges are read from the pools at scrape time
+    let autocomplete_sessions = appstate.autocomplete_used_engines.lock().await.len();
+    let autocomplete_status = appstate.autocomplete_engine_pool.status();
+    let gp_status = appstate.gp_engine_pool.status();
+
+    metrics.autocomplete_sessions.set(autocomplete_sessions as i64);
+    // engines taken out of the autocomplete pool (attributed to sessions, or used by a handler) are still in use
+    let pools = [
+        ("autocomplete", autocomplete_status, appstate.autocomplete_engine_count.load(Ordering::Relaxed)),
+        ("gp", gp_status, appstate.gp_engine_count.load(Ordering::Relaxed)),
+    ];
+    for (pool, status, owned) in pools {
+        metrics.pool_size.with_label_values(&[pool]).set(owned as i64);
+        metrics.pool_available.with_label_values(&[pool]).set(status.available as i64);
+        metrics.pool_in_use.with_label_values(&[pool]).set(owned.saturating_sub(status.available) as i64);
+    }
+
+    return ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], metrics.render()).into_response();
+}
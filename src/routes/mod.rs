@@ -1,3 +1,13 @@
+pub mod error;
 pub mod exact_match;
 pub mod fuzzy_autocomplete;
 pub mod fuzzy_match;
+pub mod fuzzy_match_stream;
+pub mod jobs;
+pub mod limits;
+pub mod match_cascade;
+pub mod metrics;
+pub mod parse;
+pub mod reconcile;
+pub mod status;
+pub mod ws_autocomplete;
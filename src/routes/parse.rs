@@ -0,0 +1,37 @@
+use std::sync::Arc;
+
+use axum::{extract::State, Extension, Json};
+use serde::{Deserialize, Serialize};
+
+use crate::{auth::ApiKey, names::{self, ParsedName}, AppState};
+
+use super::{error::{ApiError, ApiJson}, limits};
+
+
+#[derive(Debug, Deserialize)]
+pub struct ParseRequest {
+    strings: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct ParseResponse {
+    names: Vec<ParsedName>,
+}
+
+/**
+ * Splits each string into the parts of a scientific name, in the order of the strings.
+ * Nothing is matched, the dataset is not involved.
+ */
+pub async fn parse(
+    State(appstate): State<AppState>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    ApiJson(payload): ApiJson<ParseRequest>,
+    )
+-> Result<Json<ParseResponse>, ApiError> {
+    limits::check_batch(&appstate, &api_key, &payload.strings)?;
+
+    let names = payload.strings.iter()
+        .map(|string| names::parse(string))
+        .collect::<Vec<ParsedName>>();
+    return Ok(Json(ParseResponse { names }));
+}
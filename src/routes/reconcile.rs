@@ -0,0 +1,130 @@
+use std::sync::Arc;
+
+use axum::{body::Body, extract::State, http::{header, HeaderMap, StatusCode}, response::{IntoResponse, Response}, Extension};
+use serde::Deserialize;
+
+use crate::{auth::ApiKey, batch::BatchMatcher, csv_table::{self, CsvTable}, io::EngineInputData, AppState};
+
+use super::{error::{ApiError, ApiQuery}, fuzzy_match::match_in_parallel, limits};
+
+
+#[derive(Debug, Deserialize)]
+pub struct ReconcileParams {
+    // header of the column holding the names, the first column by default
+    column: Option<String>,
+    // comma separated keys of the matched item data, each one added as a column
+    fields: Option<String>,
+}
+
+// best match of a row, score is only known for fuzzy matches
+enum RowMatch {
+    Exact(EngineInputData),
+    Fuzzy(u32, EngineInputData),
+    // fuzzy match of an abbreviated genus ("E. coli"), once expanded
+    Abbreviation(u32, EngineInputData),
+    None,
+}
+
+/**
+ * Reconciles the names of a CSV/TSV file: each name is looked up exactly, like /exact_match, and if not found,
+ * fuzzy matched like /fuzzy_match. The file is returned as it was sent, with extra columns:
+ * match_name, match_type (exact, fuzzy, abbreviation or none), match_score (fuzzy matches only) and match_{field} for each field asked.
+ */
+pub async fn reconcile_csv(
+    State(appstate): State<AppState>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    ApiQuery(params): ApiQuery<ReconcileParams>,
+    headers: HeaderMap,
+    body: Body,
+    )
+-> Result<Response, ApiError> {
+    let Some(delimiter) = csv_table::delimiter_from_headers(&headers) else {
+        return Err(ApiError::unsupported_csv_type());
+    };
+    let max_body_size = appstate.server_config.max_body_size;
+    let content = axum::body::to_bytes(body, max_body_size).await
+        .map_err(|e| ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "body_too_large",
+            format!("The body could not be read whole, it is limited to {} bytes: {}", max_body_size, e)))?;
+
+    let table = CsvTable::parse(&content, delimiter).map_err(ApiError::invalid_csv)?;
+    let column = table.column_index(params.column.as_deref()).map_err(ApiError::invalid_csv)?;
+    let names = table.column_values(column);
+    limits::check_batch(&appstate, &api_key, &names)?;
+
+    let mut row_matches = names.iter()
+        .map(|name| match appstate.db_hashmap.get(name) {
+            Some(item) => RowMatch::Exact(item.clone()),
+            None => RowMatch::None,
+        })
+        .collect::<Vec<RowMatch>>();
+
+    // only names without exact match go through fuzzy matching, empty ones are left unmatched
+    let fuzzy_rows = row_matches.iter().zip(&names)
+        .enumerate()
+        .filter(|(_, (row_match, name))| matches!(row_match, RowMatch::None) && !name.is_empty())
+        .map(|(row, _)| row)
+        .collect::<Vec<usize>>();
+    let fuzzy_names = fuzzy_rows.iter().map(|row| names[*row].clone()).collect::<Vec<String>>();
+    let fuzzy_matches = match_in_parallel(&appstate, fuzzy_names, "/reconcile/csv", best_matches).await?;
+    for (row, fuzzy_match) in fuzzy_rows.into_iter().zip(fuzzy_matches) {
+        row_matches[row] = fuzzy_match;
+    }
+
+    let fields = params.fields.as_deref().unwrap_or("")
+        .split(',')
+        .map(|field| field.trim())
+        .filter(|field| !field.is_empty())
+        .collect::<Vec<&str>>();
+    let mut extra_headers = vec!["match_name".to_owned(), "match_type".to_owned(), "match_score".to_owned()];
+    extra_headers.extend(fields.iter().map(|field| format!("match_{}", field)));
+    let extra_values = row_matches.iter()
+        .map(|row_match| match_columns(row_match, &fields))
+        .collect::<Vec<Vec<String>>>();
+
+    let csv = table.write_with_columns(&extra_headers, &extra_values)
+        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "csv_error", e.to_string()))?;
+    return Ok(([(header::CONTENT_TYPE, csv_table::content_type(delimiter))], csv).into_response());
+}
+
+// best fuzzy match of each name of the chunk, if any
+fn best_matches(batch_matcher: &BatchMatcher, chunk: Vec<String>) -> (Vec<RowMatch>, u64) {
+    let mut matcher = BatchMatcher::new_matcher();
+    let mut zero_results = 0;
+    let mut result = Vec::with_capacity(chunk.len());
+    for name in chunk {
+        let (matches, abbreviation) = batch_matcher.fuzzy_match_expanded_scored(&mut matcher, name, 1);
+        let best = match (matches.into_iter().next(), abbreviation) {
+            (Some((score, item)), None) => RowMatch::Fuzzy(score, item),
+            (Some((score, item)), Some(_)) => RowMatch::Abbreviation(score, item),
+            (None, _) => {
+                zero_results += 1;
+                RowMatch::None
+            },
+        };
+        result.push(best);
+    }
+    return (result, zero_results);
+}
+
+fn match_columns(row_match: &RowMatch, fields: &[&str]) -> Vec<String> {
+    let (item, match_type, score) = match row_match {
+        RowMatch::Exact(item) => (Some(item), "exact", String::new()),
+        RowMatch::Fuzzy(score, item) => (Some(item), "fuzzy", score.to_string()),
+        RowMatch::Abbreviation(score, item) => (Some(item), "abbreviation", score.to_string()),
+        RowMatch::None => (None, "none", String::new()),
+    };
+
+    let mut columns = vec![item.map(|item| item.string.clone()).unwrap_or_default(), match_type.to_owned(), score];
+    for field in fields {
+        // strings are written as they are, other values as json
+        let value = match item.and_then(|item| item.data.get(field)) {
+            Some(serde_json::Value::String(value)) => value.clone(),
+            Some(serde_json::Value::Null) | None => String::new(),
+            Some(value) => value.to_string(),
+        };
+        columns.push(value);
+    }
+    return columns;
+}
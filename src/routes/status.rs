@@ -0,0 +1,98 @@
+use std::sync::atomic::Ordering;
+
+use axum::{Json, http::StatusCode, extract::State};
+use deadpool::Status;
+use serde::Serialize;
+
+use crate::AppState;
+
+use super::error::ApiError;
+
+
+#[derive(Serialize)]
+pub struct PoolStatus {
+    max_size: usize,
+    size: usize,
+    available: usize,
+    waiting: usize,
+}
+
+impl From<Status> for PoolStatus {
+    fn from(status: Status) -> Self {
+        return PoolStatus { max_size: status.max_size, size: status.size, available: status.available, waiting: status.waiting };
+    }
+}
+
+#[derive(Serialize)]
+pub struct DatasetStatus {
+    path: String,
+    size: usize,
+    generation: u64,
+}
+
+#[derive(Serialize)]
+pub struct StatusResponse {
+    uptime_seconds: u64,
+    dataset: DatasetStatus,
+    autocomplete_pool: PoolStatus,
+    gp_pool: PoolStatus,
+    // engines taken out of the autocomplete pool and attributed to a session
+    autocomplete_engines_in_sessions: usize,
+    autocomplete_stale_engines: u64,
+    cleanup_missing_engines: u64,
+    prefix_cache_hits: u64,
+    prefix_cache_misses: u64,
+}
+
+// liveness: if this answers, the process is up
+pub async fn healthz() -> StatusCode {
+    return StatusCode::OK;
+}
+
+/**
+ * Readiness: the dataset is loaded and both pools own at least their min_size engines.
+ * Engines taken out of a pool, attributed to sessions or used by a handler, still count as owned.
+ */
+pub async fn readyz(State(appstate): State<AppState>) -> Result<StatusCode, ApiError> {
+    if appstate.dataset.size == 0 {
+        return Err(ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "not_ready", "The dataset is empty"));
+    }
+
+    let autocomplete_engines = appstate.autocomplete_engine_count.load(Ordering::Relaxed);
+    if appstate.autocomplete_engine_pool.is_closed() || autocomplete_engines < appstate.server_config.autocomplete_pool_min_size {
+        return Err(ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "not_ready",
+            format!("Autocomplete pool has {} engines, {} expected", autocomplete_engines, appstate.server_config.autocomplete_pool_min_size)));
+    }
+
+    let gp_engines = appstate.gp_engine_count.load(Ordering::Relaxed);
+    if appstate.gp_engine_pool.is_closed() || gp_engines < appstate.server_config.gp_pool_min_size {
+        return Err(ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "not_ready",
+            format!("General purpose pool has {} engines, {} expected", gp_engines, appstate.server_config.gp_pool_min_size)));
+    }
+
+    return Ok(StatusCode::OK);
+}
+
+pub async fn status(State(appstate): State<AppState>) -> Json<StatusResponse> {
+    let metrics = &appstate.metrics;
+
+    return Json(StatusResponse {
+        uptime_seconds: appstate.started_at.elapsed().as_secs(),
+        dataset: DatasetStatus {
+            path: appstate.dataset.path.clone(),
+            size: appstate.dataset.size,
+            generation: appstate.dataset.generation,
+        },
+        autocomplete_pool: appstate.autocomplete_engine_pool.status().into(),
+        gp_pool: appstate.gp_engine_pool.status().into(),
+        autocomplete_engines_in_sessions: appstate.autocomplete_used_engines.lock().await.len(),
+        autocomplete_stale_engines: metrics.autocomplete_stale_engines.get(),
+        cleanup_missing_engines: metrics.cleanup_missing_engines.get(),
+        prefix_cache_hits: metrics.prefix_cache_hits.get(),
+        prefix_cache_misses: metrics.prefix_cache_misses.get(),
+    });
+}
@@ -0,0 +1,174 @@
+use std::sync::{atomic::{AtomicBool, Ordering}, Arc};
+
+use axum::{extract::{ws::{Message, WebSocket}, State, WebSocketUpgrade}, response::{IntoResponse, Response}, Extension};
+use deadpool::unmanaged::Object;
+use serde::Serialize;
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+use crate::{engine::EngineWrapper, io::EngineInputData, rate_limit::{ClientEngines, ClientId}, AppState};
+
+use super::{error::ApiError, fuzzy_autocomplete::autocomplete_queries, limits};
+
+
+// pushed back for each query that was not superseded by a newer one
+#[derive(Serialize)]
+pub struct WsAutocompleteResponse {
+    query: String,
+    matches: Vec<EngineInputData>,
+    // the genera an abbreviated genus ("E. coli") was expanded to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    abbreviation: Option<Vec<String>>,
+}
+
+// matches are None when the task was cancelled
+// pushed back instead of a WsAutocompleteResponse for a query that is refused, code is the one of the ApiError
+#[derive(Serialize)]
+pub struct WsErrorResponse {
+    error: WsError,
+}
+
+#[derive(Serialize)]
+pub struct WsError {
+    code: &'static str,
+    detail: String,
+}
+
+type MatchTask = JoinHandle<(Object<EngineWrapper>, String, Option<Vec<String>>, Option<Vec<EngineInputData>>)>;
+
+// counts the socket engine against its client, until dropped along with the connection
+struct EngineHold {
+    client_engines: Arc<ClientEngines>,
+    uuid: Uuid,
+}
+
+impl Drop for EngineHold {
+    fn drop(&mut self) {
+        self.client_engines.release(&self.uuid);
+    }
+}
+
+/**
+ * Each connection holds one engine of the autocomplete pool for its whole lifetime,
+ * so no session nor delay queue is needed: the engine goes back to the pool when the socket closes.
+ * Each text message is a query, answered with a WsAutocompleteResponse, or a WsErrorResponse if it is too long. A query still running when a newer one
+ * arrives is cancelled, and not answered.
+ */
+pub async fn ws_autocomplete(
+    State(appstate): State<AppState>,
+    Extension(client): Extension<ClientId>,
+    ws: WebSocketUpgrade,
+    )
+-> Response {
+    tracing::debug!(autocomplete_pool = ?appstate.autocomplete_engine_pool.status(), "ws autocomplete handler");
+
+    let uuid = Uuid::new_v4();
+    if !appstate.client_engines.try_hold(&client, uuid) {
+        appstate.metrics.client_rejections.with_label_values(&["too_many_engines"]).inc();
+        return ApiError::too_many_engines(appstate.client_engines.max_per_client()).into_response();
+    }
+    let hold = EngineHold { client_engines: appstate.client_engines.clone(), uuid };
+
+    // refuse the upgrade rather than keeping a connection open that would wait for an engine
+    let engine = match appstate.autocomplete_engine_pool.try_get() {
+        Ok(engine) => engine,
+        Err(e) => return ApiError::from(e).into_response(),
+    };
+
+    let limit = appstate.server_config.autocomplete_result_limit;
+    // axum closes the connection on longer messages before buffering them, shorter ones are checked by limits::check_string
+    return ws.max_message_size(appstate.server_config.max_body_size).on_upgrade(move |socket| async move {
+        handle_socket(socket, engine, limit, appstate).await;
+        drop(hold);
+    });
+}
+
+async fn handle_socket(mut socket: WebSocket, engine: Object<EngineWrapper>, limit: usize, appstate: AppState) {
+    // the engine is either idle here, or moved into the running task
+    let mut idle_engine = Some(engine);
+    let mut running: Option<MatchTask> = None;
+    // set to cancel the running match, once a newer query makes it stale
+    let mut cancel = Arc::new(AtomicBool::new(false));
+    // latest query received while a match was running, older ones are dropped
+    let mut pending: Option<String> = None;
+
+    loop {
+        tokio::select! {
+            msg = socket.recv() => {
+                match msg {
+                    Some(Ok(Message::Text(query))) => {
+                        if limits::check_string(&appstate, &query).is_err() {
+                            let error = WsErrorResponse { error: WsError {
+                                code: "string_too_long",
+                                detail: format!("The query is longer than the maximum of {} characters", appstate.server_config.max_string_length),
+                            }};
+                            let payload = serde_json::to_string(&error).expect("error is always serializable");
+                            if socket.send(Message::Text(payload)).await.is_err() {
+                                break;
+                            }
+                            continue;
+                        }
+                        cancel.store(true, Ordering::Relaxed);
+                        pending = Some(query);
+                    },
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => {}, // ping/pong are handled by axum, binary is ignored
+                }
+            },
+            joined = async { running.as_mut().expect("only polled while running").await }, if running.is_some() => {
+                running = None;
+                let (engine, query, abbreviation, matches) = match joined {
+                    Ok(done) => done,
+                    Err(e) => {
+                        // the engine is lost with the task, nothing more can be served
+                        tracing::error!(error = %e, "Match task error");
+                        break;
+                    },
+                };
+                idle_engine = Some(engine);
+
+                // None when cancelled because a newer keystroke arrived, the result would be stale anyway
+                if let Some(matches) = matches {
+                    if matches.is_empty() {
+                        appstate.metrics.zero_result_queries.with_label_values(&["/ws/autocomplete"]).inc();
+                    }
+
+                    // a newer keystroke arrived after the match finished, this result is stale too
+                    if pending.is_none() {
+                        let payload = serde_json::to_string(&WsAutocompleteResponse{ query, matches, abbreviation })
+                            .expect("response is always serializable");
+                        if socket.send(Message::Text(payload)).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            },
+        }
+
+        if running.is_none() {
+            if let Some(query) = pending.take() {
+                if query.trim().is_empty() {
+                    continue;
+                }
+
+                let Some(mut engine) = idle_engine.take() else {
+                    break;
+                };
+                cancel = Arc::new(AtomicBool::new(false));
+                let task_cancel = cancel.clone();
+                let (queries, abbreviation) = autocomplete_queries(&appstate, query.clone());
+                running = Some(tokio::task::spawn_blocking(move || {
+                    let matches = engine.fuzzy_match_cancellable(queries, limit, &task_cancel);
+                    return (engine, query, abbreviation, matches);
+                }));
+            }
+        }
+    }
+
+    // stop the last match, and wait for it so its engine is dropped, and thus put back in the pool
+    cancel.store(true, Ordering::Relaxed);
+    if let Some(task) = running {
+        let _ = task.await;
+    }
+    tracing::debug!("Websocket closed, engine returned to pool");
+}
@@ -0,0 +1,31 @@
+use axum_server::tls_rustls::RustlsConfig;
+
+use crate::config::ServerConfig;
+
+/**
+ * Builds the rustls config from the configured PEM files, or from a freshly generated self-signed certificate.
+ */
+pub async fn load_rustls_config(server_config: &ServerConfig) -> Result<RustlsConfig, String> {
+    // only the ring provider is compiled in, it has to be installed before building any rustls config
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    if server_config.tls_self_signed {
+        tracing::warn!("Using a self-signed certificate for localhost, do not use in production");
+        let certified_key = rcgen::generate_simple_self_signed(vec!["localhost".to_owned(), "127.0.0.1".to_owned()])
+            .map_err(|e| e.to_string())?;
+
+        return RustlsConfig::from_pem(
+            certified_key.cert.pem().into_bytes(),
+            certified_key.key_pair.serialize_pem().into_bytes())
+            .await
+            .map_err(|e| e.to_string());
+    }
+
+    // both are checked to be set when loading the config
+    let (Some(cert_path), Some(key_path)) = (&server_config.tls_cert_path, &server_config.tls_key_path) else {
+        return Err("tls_cert_path and tls_key_path must be given together".to_owned());
+    };
+    return RustlsConfig::from_pem_file(cert_path, key_path)
+        .await
+        .map_err(|e| format!("Cannot load certificate {} and key {}: {}", cert_path, key_path, e));
+}